@@ -0,0 +1,303 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use faster_hex::hex_string;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::merkletree::{Hash, HashScheme, Layout, MerkleTree, Proof, bytes_from_hex, from_hex, hex_hash, to_hex, verify_proof};
+use crate::streamhasher::{StreamHasher, for_each_line};
+
+pub const USAGE: &str = "\
+Usage:
+    civisgrid merkle build <path>
+    civisgrid merkle prove <path> <leaf>
+    civisgrid merkle verify <root> <proof.json> [<file>]
+
+<path> is a file, each line of which is a leaf, or a directory, each file of
+which is a leaf in file name order. <leaf> is a line index, from 0, or a file
+name. verify checks a proof written by prove against the root, with the leaf
+data of the proof or the content of <file>, and whether the proof was written
+for that root. Results are written as JSON.";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CliError {
+    Usage,
+    Failed(String),
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> CliError {
+        CliError::Failed(e.to_string())
+    }
+}
+
+/**
+ * Proof of a leaf as written by `prove`, with the leaf's data (hex) so that
+ * it can be checked on its own.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofDocument {
+    #[serde(with = "hex_hash")]
+    pub root: Hash,
+    pub leaf: String,
+    pub data: String,
+    pub proof: Proof,
+}
+
+/**
+ * Runs `civisgrid` with `args`, the program name excluded, and returns its JSON output.
+ * Trees are balanced with the default hash scheme, as MerkleTree::from_data.
+ */
+pub fn run(args: &[String]) -> Result<Value, CliError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["merkle", "build", path] => build(Path::new(path)),
+        ["merkle", "prove", path, leaf] => prove(Path::new(path), leaf),
+        ["merkle", "verify", root, proof] => verify(root, Path::new(proof), None),
+        ["merkle", "verify", root, proof, file] => verify(root, Path::new(proof), Some(Path::new(file))),
+        _ => Err(CliError::Usage),
+    }
+}
+
+/**
+ * Root over the leaves of `path`, hashed as they are read.
+ */
+fn build(path: &Path) -> Result<Value, CliError> {
+    let mut hasher = StreamHasher::new();
+    match Source::open(path)? {
+        Source::Lines(file) => {
+            hasher.push_lines(file)?;
+        }
+        Source::Files(files) => {
+            for file in files {
+                hasher.push(&fs::read(file)?);
+            }
+        }
+    }
+    if hasher.leaf_count() == 0 {
+        return Err(CliError::Failed(format!("No leaf in {}", path.display())));
+    }
+
+    Ok(json!({
+        "root": to_hex(&hasher.root()),
+        "leaf_count": hasher.leaf_count(),
+        "scheme": HashScheme::default(),
+        "layout": Layout::Balanced,
+    }))
+}
+
+fn prove(path: &Path, leaf: &str) -> Result<Value, CliError> {
+    let (leaves, index) = match Source::open(path)? {
+        Source::Lines(file) => {
+            let mut leaves = Vec::new();
+            for_each_line(file, |line| leaves.push(line.to_vec()))?;
+            let index = leaf.parse().map_err(|_| CliError::Failed(format!("Invalid line index {}", leaf)))?;
+            (leaves, index)
+        }
+        Source::Files(files) => {
+            let index = files.iter()
+                .position(|file| file.file_name().is_some_and(|name| name == leaf))
+                .ok_or_else(|| CliError::Failed(format!("No file {} in {}", leaf, path.display())))?;
+            let leaves = files.iter().map(fs::read).collect::<io::Result<Vec<Vec<u8>>>>()?;
+            (leaves, index)
+        }
+    };
+
+    let tree = MerkleTree::from_data(&leaves).map_err(failed)?;
+    let document = ProofDocument{
+        root: *tree.root(),
+        leaf: leaf.to_string(),
+        data: hex_string(leaves.get(index).map_or(&[][..], |data| &data[..])).unwrap(),
+        proof: tree.make_proof(index).map_err(failed)?,
+    };
+
+    Ok(serde_json::to_value(document).unwrap())
+}
+
+/**
+ * The output's `valid` is false if the proof does not hold, which is not an error.
+ * `root_matches` tells whether the proof was written for the same root.
+ */
+fn verify(root: &str, proof: &Path, file: Option<&Path>) -> Result<Value, CliError> {
+    let root = from_hex(root).ok_or_else(|| CliError::Failed(format!("Invalid root {}", root)))?;
+    let document: ProofDocument = serde_json::from_reader(BufReader::new(File::open(proof)?))
+        .map_err(|e| CliError::Failed(format!("Invalid proof {}: {}", proof.display(), e)))?;
+
+    let data = match file {
+        Some(file) => fs::read(file)?,
+        None => bytes_from_hex(&document.data).ok_or_else(|| CliError::Failed(format!("Invalid leaf data in {}", proof.display())))?,
+    };
+
+    Ok(json!({
+        "valid": verify_proof(&root, &data, &document.proof),
+        "root": to_hex(&root),
+        "root_matches": document.root == root,
+        "leaf": document.leaf,
+        "leaf_index": document.proof.leaf_index,
+        "leaf_count": document.proof.leaf_count,
+    }))
+}
+
+/**
+ * Where the leaves are read from.
+ */
+enum Source {
+    Lines(File),
+    Files(Vec<PathBuf>),
+}
+
+impl Source {
+    /**
+     * Files of a directory are sorted by name, subdirectories are skipped.
+     */
+    fn open(path: &Path) -> Result<Source, CliError> {
+        let failed = |e: io::Error| CliError::Failed(format!("{}: {}", path.display(), e));
+        if !path.is_dir() {
+            return Ok(Source::Lines(File::open(path).map_err(failed)?));
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path).map_err(failed)? {
+            let entry = entry.map_err(failed)?;
+            if entry.file_type().map_err(failed)?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+        Ok(Source::Files(files))
+    }
+}
+
+fn failed<E: ToString>(e: E) -> CliError {
+    CliError::Failed(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nodestore::tests::TestDir;
+
+    fn run_args(args: &[&str]) -> Result<Value, CliError> {
+        run(&args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>())
+    }
+
+    fn readings(amount: usize) -> Vec<String> {
+        (0..amount).map(|i| format!("meter-{},{}.5 kWh", i, i)).collect()
+    }
+
+    #[test]
+    fn lines() {
+        let dir = TestDir::new("cli-lines");
+        fs::create_dir_all(&dir.0).unwrap();
+        // A meter without a reading is an empty line
+        let mut lines = readings(11);
+        lines[4].clear();
+        let csv = dir.0.join("readings.csv");
+        fs::write(&csv, lines.join("\n") + "\n").unwrap();
+        let csv = csv.to_str().unwrap();
+
+        let tree = MerkleTree::from_data(&lines).unwrap();
+        let built = run_args(&["merkle", "build", csv]).unwrap();
+        assert_eq!(built["root"], to_hex(tree.root()));
+        assert_eq!(built["leaf_count"], 11);
+
+        let proven = run_args(&["merkle", "prove", csv, "7"]).unwrap();
+        let document: ProofDocument = serde_json::from_value(proven.clone()).unwrap();
+        assert_eq!(document.proof, tree.make_proof(7).unwrap());
+        assert_eq!(bytes_from_hex(&document.data).unwrap(), lines[7].as_bytes());
+
+        let proof = dir.0.join("proof.json");
+        fs::write(&proof, proven.to_string()).unwrap();
+        let proof = proof.to_str().unwrap();
+        let verified = run_args(&["merkle", "verify", &to_hex(tree.root()), proof]).unwrap();
+        assert_eq!(verified["valid"], true);
+        assert_eq!(verified["root_matches"], true);
+        assert_eq!(verified["leaf_index"], 7);
+
+        // Against another root, or with other data
+        let other = MerkleTree::from_data(&readings(12)).unwrap();
+        let verified = run_args(&["merkle", "verify", &to_hex(other.root()), proof]).unwrap();
+        assert_eq!(verified["valid"], false);
+        assert_eq!(verified["root_matches"], false);
+        fs::write(dir.0.join("forged"), "meter-7,0.5 kWh").unwrap();
+        let forged = dir.0.join("forged");
+        assert_eq!(run_args(&["merkle", "verify", &to_hex(tree.root()), proof, forged.to_str().unwrap()]).unwrap()["valid"], false);
+
+        // The empty line, with its empty data in the proof
+        let proven = run_args(&["merkle", "prove", csv, "4"]).unwrap();
+        assert_eq!(proven["data"], "");
+        fs::write(dir.0.join("empty.json"), proven.to_string()).unwrap();
+        let empty = dir.0.join("empty.json");
+        assert_eq!(run_args(&["merkle", "verify", &to_hex(tree.root()), empty.to_str().unwrap()]).unwrap()["valid"], true);
+
+        assert!(matches!(run_args(&["merkle", "prove", csv, "11"]), Err(CliError::Failed(_))));
+        assert!(matches!(run_args(&["merkle", "prove", csv, "seven"]), Err(CliError::Failed(_))));
+    }
+
+    #[test]
+    fn files() {
+        let dir = TestDir::new("cli-files");
+        let data = dir.0.join("data");
+        fs::create_dir_all(data.join("skipped")).unwrap();
+        // Written out of order, leaves are in name order
+        for i in (0..5).rev() {
+            fs::write(data.join(format!("day-{}.csv", i)), readings(i + 1).join("\n")).unwrap();
+        }
+        fs::write(data.join("day-5.csv"), "").unwrap();
+        let data_dir = data.to_str().unwrap();
+
+        let mut files: Vec<Vec<u8>> = (0..5).map(|i| readings(i + 1).join("\n").into_bytes()).collect();
+        files.push(Vec::new());
+        let tree = MerkleTree::from_data(&files).unwrap();
+        assert_eq!(run_args(&["merkle", "build", data_dir]).unwrap()["root"], to_hex(tree.root()));
+
+        let proof = dir.0.join("proof.json");
+        // The empty file, from the data in the proof
+        let proven = run_args(&["merkle", "prove", data_dir, "day-5.csv"]).unwrap();
+        fs::write(&proof, proven.to_string()).unwrap();
+        let verified = run_args(&["merkle", "verify", &to_hex(tree.root()), proof.to_str().unwrap()]).unwrap();
+        assert_eq!(verified["valid"], true);
+        assert_eq!(verified["leaf_index"], 5);
+
+        let proven = run_args(&["merkle", "prove", data_dir, "day-3.csv"]).unwrap();
+        assert_eq!(proven["proof"]["leaf_index"], 3);
+        fs::write(&proof, proven.to_string()).unwrap();
+
+        // The file itself is checked
+        let day = data.join("day-3.csv");
+        let verify = |file: &Path| run_args(&["merkle", "verify", &to_hex(tree.root()), proof.to_str().unwrap(), file.to_str().unwrap()]);
+        assert_eq!(verify(&day).unwrap()["valid"], true);
+        fs::write(&day, "tampered").unwrap();
+        assert_eq!(verify(&day).unwrap()["valid"], false);
+
+        assert!(matches!(run_args(&["merkle", "prove", data_dir, "day-9.csv"]), Err(CliError::Failed(_))));
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TestDir::new("cli-errors");
+        fs::create_dir_all(&dir.0).unwrap();
+        let empty = dir.0.join("empty.csv");
+        fs::write(&empty, "").unwrap();
+        let empty = empty.to_str().unwrap();
+
+        assert_eq!(run_args(&[]), Err(CliError::Usage));
+        assert_eq!(run_args(&["merkle", "plant", empty]), Err(CliError::Usage));
+        assert_eq!(run_args(&["merkle", "build"]), Err(CliError::Usage));
+
+        assert!(matches!(run_args(&["merkle", "build", empty]), Err(CliError::Failed(_))));
+        assert!(matches!(run_args(&["merkle", "prove", empty, "0"]), Err(CliError::Failed(_))));
+        assert!(matches!(run_args(&["merkle", "build", "/nonexistent/readings.csv"]), Err(CliError::Failed(_))));
+
+        // Malformed roots and proof documents
+        assert!(matches!(run_args(&["merkle", "verify", "abcd", empty]), Err(CliError::Failed(_))));
+        assert!(matches!(run_args(&["merkle", "verify", &"00".repeat(32), empty]), Err(CliError::Failed(_))));
+        let proof = dir.0.join("proof.json");
+        fs::write(&proof, r#"{"root":"00","leaf":"0","data":"","proof":{}}"#).unwrap();
+        assert!(matches!(run_args(&["merkle", "verify", &"00".repeat(32), proof.to_str().unwrap()]), Err(CliError::Failed(_))));
+    }
+}
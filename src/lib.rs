@@ -1,5 +1,17 @@
 // Shared code goes here. It can be imported via `use <cratename>`::*
 
+pub mod merkletree;
+pub mod sumtree;
+pub mod sortedtree;
+pub mod merklelog;
+pub mod sparsetree;
+pub mod merkletrie;
+pub mod nodestore;
+pub mod sync;
+pub mod streamhasher;
+pub mod render;
+pub mod cli;
+
 pub fn import_me() -> () {
     println!("Stuff");
 }
\ No newline at end of file
@@ -1,32 +1,37 @@
-mod merkletree;
-
-// Refactor to mod merkletree{MerkleTree, Proof}
 // Owned children implementation requires only shared read-only ownership of children.
 // Adding a parent reference to each node appears to require shared mutable ownership (RefCell).
 
 // https://github.com/hyperledger/sawtooth-core/blob/master/validator/src/state/merkle.rs
 // Hash trees allow _efficient and secure verification_ of the contents of large data structures
 
-use civisgrid::import_me;
-
-fn main() -> () {
-    // let mut b = Box::new(4); // allocated on the heapz
-    // *b = 123; // Deref trait
-    // dbg!(b);
-
-    println!("+++ CIVISgrid +++");
-
-    import_me();
-
-    let mut data: Vec<Vec<u8>> = Vec::new();
-    for d in 1..=9 {
-        // data.push(format!("Id = {}", d).into_bytes());
-        data.push(vec![d as u8]);
+use std::env;
+use std::process;
+
+use serde_json::json;
+
+use civisgrid::cli::{self, CliError};
+
+/**
+ * Prints the JSON result of the command. Exits with 1 when a proof does
+ * not verify, and with 2 on errors, reported as `{"error": ..}` on stderr.
+ */
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match cli::run(&args) {
+        Ok(output) => {
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            if output["valid"] == false {
+                process::exit(1);
+            }
+        }
+        Err(CliError::Usage) => {
+            eprintln!("{}", cli::USAGE);
+            process::exit(2);
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("{}", json!({"error": message}));
+            process::exit(2);
+        }
     }
-    let refs: Vec<&[u8]> = data.iter().map(|d| d.as_slice()).collect();
-
-    let tree = merkletree::MerkleTree::from_data(&refs);
-    dbg!(&tree);
-    let proof = tree.make_proof(refs[5]).unwrap();
-    assert!(tree.authenticate(refs[5], &proof));
 }
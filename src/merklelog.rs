@@ -0,0 +1,433 @@
+use std::marker::PhantomData;
+
+use serde::{Serialize, Deserialize};
+
+use crate::merkletree::{Hash, HashScheme, Layout, MerkleError, MerkleHasher, Proof, ProofStep, Side, Sha3_256, hex_hashes};
+use crate::merkletree::verify_proof_with_scheme;
+
+/**
+ * Proof that the log of `old_size` leaves is a prefix of the log of `new_size` leaves,
+ * i.e. that history was only appended to and never rewritten (RFC 6962 2.1.2).
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub old_size: usize,
+    pub new_size: usize,
+    #[serde(with = "hex_hashes")]
+    pub path: Vec<Hash>,
+}
+
+/**
+ * Append-only Merkle log, as in RFC 6962 (Certificate Transparency).
+ *
+ * The tree over the first n leaves has the same shape and root as a balanced
+ * MerkleTree with the Rfc6962 scheme. Only the roots of perfect subtrees are
+ * kept, so appending a leaf costs O(log n) hashes and every past tree size
+ * can still be proven.
+ */
+pub struct MerkleLog<H = Sha3_256> {
+    levels: Vec<Vec<Hash>>, // levels[l][j]: root of the perfect subtree over leaves [j * 2^l, (j + 1) * 2^l)
+    hasher: PhantomData<fn() -> H>,
+}
+impl<H> Default for MerkleLog<H> {
+    fn default() -> Self {
+        MerkleLog{
+            levels: vec![Vec::new()],
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl MerkleLog {
+    pub fn new() -> MerkleLog {
+        MerkleLog::default()
+    }
+}
+
+impl<H: MerkleHasher> MerkleLog<H> {
+    pub fn size(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /**
+     * Returns the index of the new leaf.
+     */
+    pub fn append(&mut self, data: &[u8]) -> usize {
+        let index = self.size();
+        let mut hash = HashScheme::Rfc6962.hash_leaf::<H>(data);
+        self.levels[0].push(hash);
+
+        // An even number of nodes completes a perfect subtree on the upper level
+        let mut level = 0;
+        while self.levels[level].len() % 2 == 0 {
+            let nodes = &self.levels[level];
+            hash = HashScheme::Rfc6962.hash_branch::<H>(&nodes[nodes.len() - 2], &hash);
+
+            level += 1;
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level].push(hash);
+        }
+
+        index
+    }
+
+    /**
+     * Root of the current log. The root of the empty log is the hash of no data.
+     */
+    pub fn root(&self) -> Hash {
+        self.subtree_root(0, self.size())
+    }
+
+    pub fn root_at(&self, size: usize) -> Result<Hash, MerkleError> {
+        if size > self.size() {
+            return Err(MerkleError::SizeMismatch{size, available: self.size()});
+        }
+
+        Ok(self.subtree_root(0, size))
+    }
+
+    /**
+     * Inclusion proof of the leaf at `leaf_index` in the log as it was at `size` leaves.
+     */
+    pub fn prove_inclusion(&self, leaf_index: usize, size: usize) -> Result<Proof, MerkleError> {
+        if size > self.size() {
+            return Err(MerkleError::SizeMismatch{size, available: self.size()});
+        }
+        if leaf_index >= size {
+            return Err(MerkleError::UnknownLeaf{index: leaf_index, leaf_count: size});
+        }
+
+        let mut path = Vec::new();
+        self.inclusion_path(leaf_index, 0, size, &mut path);
+
+        Ok(Proof{
+            leaf_index,
+            leaf_count: size,
+            layout: Layout::Balanced,
+            path,
+        })
+    }
+
+    pub fn prove_consistency(&self, old_size: usize, new_size: usize) -> Result<ConsistencyProof, MerkleError> {
+        if new_size > self.size() {
+            return Err(MerkleError::SizeMismatch{size: new_size, available: self.size()});
+        }
+        if old_size > new_size {
+            return Err(MerkleError::SizeMismatch{size: old_size, available: new_size});
+        }
+
+        let mut path = Vec::new();
+        if old_size > 0 && old_size < new_size {
+            self.consistency_path(old_size, 0, new_size, true, &mut path);
+        }
+
+        Ok(ConsistencyProof{
+            old_size,
+            new_size,
+            path,
+        })
+    }
+
+    /**
+     * PATH(m, D[start:end]), from the leaf's sibling up.
+     */
+    fn inclusion_path(&self, m: usize, start: usize, end: usize, path: &mut Vec<ProofStep>) {
+        if end - start == 1 {
+            return;
+        }
+
+        let k = split(end - start);
+        if m < k {
+            self.inclusion_path(m, start, start + k, path);
+            path.push(ProofStep{side: Side::Right, hash: self.subtree_root(start + k, end)});
+        } else {
+            self.inclusion_path(m - k, start + k, end, path);
+            path.push(ProofStep{side: Side::Left, hash: self.subtree_root(start, start + k)});
+        }
+    }
+
+    /**
+     * SUBPROOF(m, D[start:end], complete), `complete` is true while the old tree
+     * is a complete subtree of the new one, whose root the verifier already knows.
+     */
+    fn consistency_path(&self, m: usize, start: usize, end: usize, complete: bool, path: &mut Vec<Hash>) {
+        if m == end - start {
+            if !complete {
+                path.push(self.subtree_root(start, end));
+            }
+            return;
+        }
+
+        let k = split(end - start);
+        if m <= k {
+            self.consistency_path(m, start, start + k, complete, path);
+            path.push(self.subtree_root(start + k, end));
+        } else {
+            self.consistency_path(m - k, start + k, end, false, path);
+            path.push(self.subtree_root(start, start + k));
+        }
+    }
+
+    /**
+     * MTH(D[start:end]), looked up when the range is a perfect subtree.
+     */
+    fn subtree_root(&self, start: usize, end: usize) -> Hash {
+        let count = end - start;
+        if count == 0 {
+            return H::hash(&[]);
+        }
+
+        if count.is_power_of_two() && start % count == 0 {
+            return self.levels[count.trailing_zeros() as usize][start / count];
+        }
+
+        let k = split(count);
+        HashScheme::Rfc6962.hash_branch::<H>(&self.subtree_root(start, start + k), &self.subtree_root(start + k, end))
+    }
+}
+
+/**
+ * Largest power of 2 smaller than `count` (count > 1).
+ */
+fn split(count: usize) -> usize {
+    count.next_power_of_two() / 2
+}
+
+/**
+ * Inclusion of `data` in the log whose root at `proof.leaf_count` leaves is `root`.
+ */
+pub fn verify_inclusion<H: MerkleHasher>(root: &Hash, data: &[u8], proof: &Proof) -> bool {
+    proof.layout == Layout::Balanced && verify_proof_with_scheme::<H>(HashScheme::Rfc6962, root, data, proof)
+}
+
+/**
+ * Checks that `old_root` (at `proof.old_size` leaves) is a prefix of `new_root`
+ * (at `proof.new_size` leaves), as in RFC 9162 2.1.4.2.
+ */
+pub fn verify_consistency<H: MerkleHasher>(old_root: &Hash, new_root: &Hash, proof: &ConsistencyProof) -> bool {
+    let (first, second) = (proof.old_size, proof.new_size);
+    if first > second {
+        return false;
+    }
+    if first == second {
+        return proof.path.is_empty() && old_root == new_root;
+    }
+    if first == 0 {
+        // The empty log is a prefix of every log
+        return proof.path.is_empty();
+    }
+
+    // When the old tree is a complete subtree its root is omitted from the proof
+    let old_subtree = match first.is_power_of_two() {
+        true => Some(old_root),
+        false => None,
+    };
+    let mut path = old_subtree.into_iter().chain(proof.path.iter());
+
+    let seed = match path.next() {
+        Some(hash) => *hash,
+        None => return false,
+    };
+
+    let (mut first_node, mut second_node) = (first - 1, second - 1);
+    while first_node & 1 == 1 {
+        first_node >>= 1;
+        second_node >>= 1;
+    }
+
+    let (mut first_root, mut second_root) = (seed, seed);
+    for hash in path {
+        if second_node == 0 {
+            return false;
+        }
+
+        if first_node & 1 == 1 || first_node == second_node {
+            first_root = HashScheme::Rfc6962.hash_branch::<H>(hash, &first_root);
+            second_root = HashScheme::Rfc6962.hash_branch::<H>(hash, &second_root);
+            while first_node & 1 == 0 && first_node != 0 {
+                first_node >>= 1;
+                second_node >>= 1;
+            }
+        } else {
+            second_root = HashScheme::Rfc6962.hash_branch::<H>(&second_root, hash);
+        }
+
+        first_node >>= 1;
+        second_node >>= 1;
+    }
+
+    first_root == *old_root && second_root == *new_root && second_node == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkletree::{MerkleTree, Sha256, from_hex, to_hex};
+
+    fn make_data(amount: usize) -> Vec<Vec<u8>> {
+        (1..=amount).map(|d| format!("Trade {}", d).into_bytes()).collect()
+    }
+
+    fn make_log(data: &[Vec<u8>]) -> MerkleLog<Sha256> {
+        let mut log = MerkleLog::<Sha256>::default();
+        for d in data {
+            log.append(d);
+        }
+
+        log
+    }
+
+    fn ct_leaves() -> Vec<Vec<u8>> {
+        vec![
+            b"".to_vec(), b"\x00".to_vec(), b"\x10".to_vec(), b"\x20\x21".to_vec(), b"\x30\x31".to_vec(),
+            b"\x40\x41\x42\x43".to_vec(), b"\x50\x51\x52\x53\x54\x55\x56\x57".to_vec(),
+            b"\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f".to_vec(),
+        ]
+    }
+
+    fn hexes(hashes: &[Hash]) -> Vec<String> {
+        hashes.iter().map(to_hex).collect()
+    }
+
+    #[test]
+    fn append_matches_tree_roots() {
+        let data = make_data(70);
+        let mut log = MerkleLog::<Sha256>::default();
+        assert_eq!(log.root(), Sha256::hash(&[]));
+
+        for (i, d) in data.iter().enumerate() {
+            assert_eq!(log.append(d), i);
+            let tree = MerkleTree::<Sha256>::build(&data[..=i], HashScheme::Rfc6962, Layout::Balanced).unwrap();
+            assert_eq!(log.root(), *tree.root());
+        }
+
+        // Past roots stay available
+        for size in 1..=data.len() {
+            let tree = MerkleTree::<Sha256>::build(&data[..size], HashScheme::Rfc6962, Layout::Balanced).unwrap();
+            assert_eq!(log.root_at(size).unwrap(), *tree.root());
+        }
+        assert!(matches!(log.root_at(71), Err(MerkleError::SizeMismatch{size: 71, available: 70})));
+    }
+
+    #[test]
+    fn certificate_transparency_proofs() {
+        let log = make_log(&ct_leaves());
+        assert_eq!(to_hex(&log.root()), "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328");
+
+        let path: Vec<Hash> = log.prove_inclusion(5, 8).unwrap().path.iter().map(|step| step.hash).collect();
+        assert_eq!(hexes(&path), [
+            "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+            "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+            "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        ]);
+
+        assert_eq!(hexes(&log.prove_consistency(1, 8).unwrap().path), [
+            "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+            "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+            "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+        ]);
+        assert_eq!(hexes(&log.prove_consistency(6, 8).unwrap().path), [
+            "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+            "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+            "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        ]);
+        assert_eq!(hexes(&log.prove_consistency(2, 5).unwrap().path), [
+            "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+            "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+        ]);
+    }
+
+    #[test]
+    fn inclusion_at_past_sizes() {
+        let data = make_data(33);
+        let log = make_log(&data);
+
+        for size in 1..=data.len() {
+            let root = log.root_at(size).unwrap();
+            for (i, d) in data[..size].iter().enumerate() {
+                let proof = log.prove_inclusion(i, size).unwrap();
+                assert!(verify_inclusion::<Sha256>(&root, d, &proof));
+                assert!(!verify_inclusion::<Sha256>(&root, b"forged", &proof));
+            }
+        }
+
+        assert!(matches!(log.prove_inclusion(3, 3), Err(MerkleError::UnknownLeaf{index: 3, leaf_count: 3})));
+        assert!(matches!(log.prove_inclusion(0, 34), Err(MerkleError::SizeMismatch{..})));
+    }
+
+    #[test]
+    fn consistency_between_sizes() {
+        let data = make_data(33);
+        let log = make_log(&data);
+
+        for new_size in 0..=data.len() {
+            let new_root = log.root_at(new_size).unwrap();
+            for old_size in 0..=new_size {
+                let old_root = log.root_at(old_size).unwrap();
+                let proof = log.prove_consistency(old_size, new_size).unwrap();
+                assert!(verify_consistency::<Sha256>(&old_root, &new_root, &proof), "{} -> {}", old_size, new_size);
+
+                if old_size > 0 && old_size < new_size {
+                    // Roots from a different tree size
+                    assert!(!verify_consistency::<Sha256>(&new_root, &new_root, &proof));
+
+                    let mut tampered = proof.clone();
+                    tampered.path[0][0] ^= 1;
+                    assert!(!verify_consistency::<Sha256>(&old_root, &new_root, &tampered));
+
+                    let mut truncated = proof.clone();
+                    truncated.path.pop();
+                    assert!(!verify_consistency::<Sha256>(&old_root, &new_root, &truncated));
+                }
+            }
+        }
+
+        assert!(matches!(log.prove_consistency(5, 4), Err(MerkleError::SizeMismatch{size: 5, available: 4})));
+        assert!(matches!(log.prove_consistency(5, 34), Err(MerkleError::SizeMismatch{..})));
+    }
+
+    #[test]
+    fn rewritten_history() {
+        let data = make_data(20);
+        let honest = make_log(&data);
+        let old_root = honest.root_at(11).unwrap();
+
+        // The exchange rewrites trade 4 then keeps appending
+        let mut rewritten_data = data.clone();
+        rewritten_data[3] = b"Trade 4 (rewritten)".to_vec();
+        let rewritten = make_log(&rewritten_data);
+
+        let proof = rewritten.prove_consistency(11, 20).unwrap();
+        assert!(!verify_consistency::<Sha256>(&old_root, &rewritten.root(), &proof));
+        assert!(verify_consistency::<Sha256>(&old_root, &honest.root(), &honest.prove_consistency(11, 20).unwrap()));
+    }
+
+    #[test]
+    fn consistency_proof_serde() {
+        let log = make_log(&make_data(9));
+        let proof = log.prove_consistency(3, 9).unwrap();
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.contains(&to_hex(&proof.path[0])));
+        let decoded: ConsistencyProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+
+        assert!(serde_json::from_str::<ConsistencyProof>(r#"{"old_size":1,"new_size":2,"path":["00"]}"#).is_err());
+        assert_eq!(from_hex(&to_hex(&log.root())), Some(log.root()));
+    }
+
+    #[test]
+    fn default_hasher() {
+        let mut log = MerkleLog::new();
+        let data = make_data(5);
+        for d in &data {
+            log.append(d);
+        }
+
+        let tree = MerkleTree::from_data(&data).unwrap();
+        assert_eq!(log.root(), *tree.root());
+        assert!(crate::merkletree::verify_proof(tree.root(), &data[2], &log.prove_inclusion(2, 5).unwrap()));
+    }
+}
@@ -1,256 +1,1261 @@
+use std::convert::TryInto;
+use std::error::Error;
 use std::fmt;
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::hash::{Hash, Hasher};
-use std::collections::HashMap;
-
-use sha3::{Sha3_256, Digest};
-use faster_hex::{hex_string};
-
-#[derive(Debug, Clone)]
-enum NodeType<'a> {
-    Branch{
-        left: Rc<Node<'a>>, // shared ownership
-        right: Rc<Node<'a>>, // shared ownership
-    },
-    Leaf{ 
-        data: &'a [u8]
-    },
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use sha3::Digest;
+use sha3::digest::generic_array::typenum::U32;
+use faster_hex::{hex_string, hex_decode};
+use rayon::prelude::*;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+use crate::nodestore::NodeStore;
+
+/**
+ * Raw digest of a tree node.
+ */
+pub type Hash = [u8; 32];
+
+pub fn to_hex(hash: &Hash) -> String {
+    hex_string(hash).unwrap()
+}
+
+pub fn from_hex(hex: &str) -> Option<Hash> {
+    let mut hash = [0u8; 32];
+    if hex.len() != 2 * hash.len() {
+        return None;
+    }
+
+    hex_decode(hex.as_bytes(), &mut hash).ok().map(|_| hash)
+}
+
+/**
+ * Bytes of a hex string of any even length. faster-hex rejects empty input,
+ * which is the data of an empty leaf.
+ */
+pub(crate) fn bytes_from_hex(hex: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; hex.len() / 2];
+    match hex.len() % 2 {
+        0 if hex.is_empty() => Some(bytes),
+        0 => hex_decode(hex.as_bytes(), &mut bytes).ok().map(|_| bytes),
+        _ => None,
+    }
+}
+
+/**
+ * Serializes a Hash as a hex string, e.g. `#[serde(with = "hex_hash")]`.
+ */
+pub mod hex_hash {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        from_hex(&hex).ok_or_else(|| serde::de::Error::custom("expected a 32 bytes hex string"))
+    }
 }
+
+pub use sha3::{Sha3_256, Keccak256};
+pub use sha2::Sha256;
+
+/**
+ * Hash function of the tree, fed with the chunks of a node in order.
+ * Implemented by every Digest with a 32 bytes output (Sha3_256, Keccak256,
+ * Sha256, ..) and by Blake2b256.
+ */
+pub trait MerkleHasher {
+    fn hash(chunks: &[&[u8]]) -> Hash;
+}
+impl<D: Digest<OutputSize = U32>> MerkleHasher for D {
+    fn hash(chunks: &[&[u8]]) -> Hash {
+        let mut hasher = D::new();
+        for chunk in chunks {
+            hasher.input(chunk);
+        }
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(hasher.result().as_slice());
+
+        hash
+    }
+}
+
+/**
+ * BLAKE2b parametrized for a 32 bytes output, i.e. not a truncated BLAKE2b-512.
+ */
+pub struct Blake2b256;
+impl MerkleHasher for Blake2b256 {
+    fn hash(chunks: &[&[u8]]) -> Hash {
+        use blake2::VarBlake2b;
+        use blake2::digest::{Input, VariableOutput};
+
+        let mut hasher = VarBlake2b::new(32).unwrap();
+        for chunk in chunks {
+            hasher.input(chunk);
+        }
+
+        let mut hash = [0u8; 32];
+        hasher.variable_result(|result| hash.copy_from_slice(result));
+
+        hash
+    }
+}
+
+/**
+ * How leaves and branches are hashed.
+ *
+ * Rfc6962 (default) prefixes leaf data with 0x00 and branch children with 0x01,
+ * so that an internal node can never be passed off as a leaf (second preimage).
+ * Legacy is only kept to reproduce older roots: leaves are hashed without prefix
+ * and branches over the hex strings of their children, as the first trees were.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HashScheme {
+    #[default]
+    Rfc6962,
+    Legacy,
+}
+
+pub(crate) const LEAF_PREFIX: u8 = 0x00;
+pub(crate) const BRANCH_PREFIX: u8 = 0x01;
+
+/**
+ * Fewest nodes hashed by a single rayon task, below that splitting costs more than it saves.
+ */
+const PAR_MIN_LEN: usize = 1024;
+
+/**
+ * First byte of every binary encoding, then a tag byte for what is encoded.
+ */
+const ENCODING_VERSION: u8 = 1;
+const ROOT_TAG: u8 = b'R';
+const PROOF_TAG: u8 = b'P';
+const TREE_TAG: u8 = b'T';
+
+impl HashScheme {
+    pub fn hash_leaf<H: MerkleHasher>(self, data: &[u8]) -> Hash {
+        match self {
+            HashScheme::Rfc6962 => H::hash(&[&[LEAF_PREFIX], data]),
+            HashScheme::Legacy => H::hash(&[data]),
+        }
+    }
+
+    pub fn hash_branch<H: MerkleHasher>(self, left: &Hash, right: &Hash) -> Hash {
+        match self {
+            HashScheme::Rfc6962 => H::hash(&[&[BRANCH_PREFIX], left, right]),
+            HashScheme::Legacy => H::hash(&[to_hex(left).as_bytes(), to_hex(right).as_bytes()]),
+        }
+    }
+}
+
+/**
+ * Serializes a list of Hash as hex strings, e.g. `#[serde(with = "hex_hashes")]`.
+ */
+pub mod hex_hashes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hashes: &[Hash], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(hashes.iter().map(to_hex))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Hash>, D::Error> {
+        let hexes = Vec::<String>::deserialize(deserializer)?;
+        hexes.iter()
+            .map(|hex| from_hex(hex).ok_or_else(|| serde::de::Error::custom("expected a 32 bytes hex string")))
+            .collect()
+    }
+}
+
+/**
+ * Failure of an operation on a Merkle structure.
+ * Inputs from peers, such as proofs and sync responses, never cause a panic:
+ * they are reported as errors, or fail verification.
+ */
 #[derive(Debug)]
-struct Node<'a> {
-    r#type: NodeType<'a>,
-    label: String,
-    parent: RefCell<Weak<Node<'a>>>, // interior mutability
+pub enum MerkleError {
+    /**
+     * No data to build a tree from, or no leaf to prove.
+     */
+    EmptyInput,
+    UnknownLeaf { index: usize, leaf_count: usize },
+    UnknownKey,
+    /**
+     * Key given twice, or present where its absence was to be proven.
+     */
+    KeyExists,
+    /**
+     * Proof that does not fit the tree it is about.
+     */
+    MalformedProof,
+    /**
+     * Tree size beyond the available one, e.g. the size of a log.
+     */
+    SizeMismatch { size: usize, available: usize },
+    /**
+     * Trees with different hash schemes or layouts.
+     */
+    IncompatibleTrees,
+    SumOverflow,
+    /**
+     * Node referenced by its hash but absent from the store.
+     */
+    MissingNode(Hash),
+    CorruptNode(Hash),
+    /**
+     * I/O failure of a node store or of the stream to a peer.
+     */
+    Storage(io::Error),
+    /**
+     * Request refused by the peer, with its reason.
+     */
+    Rejected(String),
+    /**
+     * Peer response outside the sync protocol: unexpected, beyond the limits
+     * of the sync, or with leaves that do not hash to the root it announced.
+     */
+    ProtocolViolation(String),
+    /**
+     * Encoded tree, proof or root that is truncated, of another version or inconsistent.
+     */
+    InvalidEncoding,
 }
-impl<'a> Hash for Node<'a> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        for b in self.label.as_bytes() {
-            state.write_u8(*b);
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MerkleError::EmptyInput => write!(f, "Empty merkle tree"),
+            MerkleError::UnknownLeaf{index, leaf_count} => write!(f, "Leaf index {} out of range for {} leaves", index, leaf_count),
+            MerkleError::UnknownKey => write!(f, "Key not found"),
+            MerkleError::KeyExists => write!(f, "Key already in the tree"),
+            MerkleError::MalformedProof => write!(f, "Malformed proof"),
+            MerkleError::SizeMismatch{size, available} => write!(f, "Tree size {} larger than {}", size, available),
+            MerkleError::IncompatibleTrees => write!(f, "Trees with different hash schemes or layouts"),
+            MerkleError::SumOverflow => write!(f, "Sum overflow"),
+            MerkleError::MissingNode(hash) => write!(f, "Missing node {}", to_hex(hash)),
+            MerkleError::CorruptNode(hash) => write!(f, "Invalid node {}", to_hex(hash)),
+            MerkleError::Storage(e) => write!(f, "Storage error: {}", e),
+            MerkleError::Rejected(reason) => write!(f, "Request rejected by the peer: {}", reason),
+            MerkleError::ProtocolViolation(reason) => write!(f, "Protocol violation by the peer: {}", reason),
+            MerkleError::InvalidEncoding => write!(f, "Invalid encoding"),
         }
     }
 }
 
+impl Error for MerkleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MerkleError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MerkleError {
+    fn from(e: io::Error) -> MerkleError {
+        MerkleError::Storage(e)
+    }
+}
+
+/**
+ * Side of the sibling: Left means parent = H(sibling || node).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub side: Side,
+    #[serde(with = "hex_hash")]
+    pub hash: Hash,
+}
+
+/**
+ * Shape of the tree, given the number of leaves.
+ *
+ * Balanced: nodes of every level are paired left to right and an odd node out
+ * is moved up unchanged (the RFC 6962 shape).
+ * Complete: every level is full except the deepest one, which is filled from
+ * the left. See MerkleTree::complete_from_data.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Layout {
+    #[default]
+    Balanced,
+    Complete,
+}
+
+/**
+ * Inclusion proof of the leaf at `leaf_index` in a tree of `leaf_count` leaves.
+ * The path goes from the leaf's sibling up to the root's children.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    #[serde(default)]
+    pub layout: Layout,
+    pub path: Vec<ProofStep>,
+}
+
+impl Proof {
+    /**
+     * Version, tag, layout, leaf index and count (u64 BE), then the path hashes.
+     * Sides are left out: they follow from the index and count.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(19 + 32 * self.path.len());
+        bytes.extend_from_slice(&[ENCODING_VERSION, PROOF_TAG, encode_layout(self.layout)]);
+        bytes.extend_from_slice(&(self.leaf_index as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.leaf_count as u64).to_be_bytes());
+        for step in &self.path {
+            bytes.extend_from_slice(&step.hash);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Proof, MerkleError> {
+        let mut decoder = Decoder::new(bytes, PROOF_TAG)?;
+        let layout = decode_layout(decoder.byte()?)?;
+        let leaf_index = decoder.usize()?;
+        let leaf_count = decoder.usize()?;
+
+        let sides = path_sides(layout, leaf_index, leaf_count).ok_or(MerkleError::InvalidEncoding)?;
+        let mut path = Vec::with_capacity(sides.len());
+        for side in sides {
+            path.push(ProofStep{side, hash: decoder.hash()?});
+        }
+        decoder.finish()?;
+
+        Ok(Proof{
+            leaf_index,
+            leaf_count,
+            layout,
+            path,
+        })
+    }
+}
+
+/**
+ * Inclusion proof of several leaves at once, sorted by index.
+ * Siblings shared by the leaves' paths, or computable from the leaves themselves,
+ * are not repeated: `hashes` holds the missing ones only, level by level from the leaves up.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiProof {
+    pub leaf_indices: Vec<usize>,
+    pub leaf_count: usize,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(with = "hex_hashes")]
+    pub hashes: Vec<Hash>,
+}
+
 /**
  * Balanced binary Merkle tree.
  * Balanced: left and right subtrees of every node differ in height by no more than 1.
+ *
+ * Digests are stored level by level in a single contiguous array, leaves first
+ * and root last. The first `pairs[level]` pairs of a level are hashed into the
+ * upper level, the remaining nodes are copied unchanged after them.
+ *
+ * SHA3-256 is the default hash function, see MerkleTree::build for the others.
  */
-pub struct MerkleTree<'a> {
-    root: Rc<Node<'a>>,
-    nodes: HashMap<String, Rc<Node<'a>>>, // indexed by label
-    // data_map: HashMap<String, Rc<Node<'a>>>,
+pub struct MerkleTree<H = Sha3_256> {
+    digests: Vec<Hash>,
+    offsets: Vec<usize>, // start of each level in digests, plus the end of the root level
+    pairs: Vec<usize>, // number of branches built from each level
+    leaf_count: usize,
+    scheme: HashScheme,
+    layout: Layout,
+    hasher: PhantomData<fn() -> H>, // the tree does not own a hasher, it is Send + Sync regardless of H
+}
+impl<H> Clone for MerkleTree<H> {
+    fn clone(&self) -> Self {
+        MerkleTree{
+            digests: self.digests.clone(),
+            offsets: self.offsets.clone(),
+            pairs: self.pairs.clone(),
+            leaf_count: self.leaf_count,
+            scheme: self.scheme,
+            layout: self.layout,
+            hasher: PhantomData,
+        }
+    }
 }
-impl<'a> fmt::Debug for MerkleTree<'a> {
+impl<H: MerkleHasher> fmt::Debug for MerkleTree<H> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:#?}", self.root)
+        for level in (0..self.height() + 1).rev() {
+            let hashes: Vec<String> = self.level(level).iter().map(to_hex).collect();
+            writeln!(f, "{}: {:?}", level, hashes)?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Serde form of a tree: every level digest, as in the binary encoding.
+ * The shape follows from the leaf count and layout.
+ */
+#[derive(Serialize)]
+struct TreeRef<'a> {
+    leaf_count: usize,
+    scheme: HashScheme,
+    layout: Layout,
+    #[serde(with = "hex_hashes")]
+    digests: &'a [Hash],
+}
+
+#[derive(Deserialize)]
+struct TreeRepr {
+    leaf_count: usize,
+    scheme: HashScheme,
+    layout: Layout,
+    #[serde(with = "hex_hashes")]
+    digests: Vec<Hash>,
+}
+
+impl<H> Serialize for MerkleTree<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tree = TreeRef{
+            leaf_count: self.leaf_count,
+            scheme: self.scheme,
+            layout: self.layout,
+            digests: &self.digests,
+        };
+        tree.serialize(serializer)
+    }
+}
+
+impl<'de, H: MerkleHasher> Deserialize<'de> for MerkleTree<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree = TreeRepr::deserialize(deserializer)?;
+        MerkleTree::from_digests(tree.digests, tree.leaf_count, tree.scheme, tree.layout)
+            .map_err(serde::de::Error::custom)
     }
 }
 
-impl<'a> MerkleTree<'a> {
+impl MerkleTree {
     /**
-     * Time complexity wrt data items: n + log2(n)
-     * 
-     * NEW ALGO TODO for proper+complete binary tree
-     * - let complete_tree = make_tree(leaves.rev()[0..floor(log2(leaves.length))])
-     *     ^--> create a proper and complete tree starting from the _last_ "smallest power of 2" leaves
-     * - add new level s.t. 
-     * 
+     * Time complexity wrt data items: n hashes for the leaves + n-1 for the branches.
+     * The digests array is allocated once, no other allocation happens per node.
      */
-    pub fn from_data(data: &[&'a[u8]]) -> MerkleTree<'a> {
-        // Stores branches for the 'current' tree level to be processed
-        let mut nodes_stack: Vec<Rc<Node<'a>>> = vec![];
-        let mut nodes_map: HashMap<String, Rc<Node<'a>>> = HashMap::new();
-        // let mut leaves_map = HashMap::new();
+    pub fn from_data<T: AsRef<[u8]>>(data: &[T]) -> Result<MerkleTree, MerkleError> {
+        MerkleTree::build(data, HashScheme::default(), Layout::Balanced)
+    }
 
-        // The balanced binary Merkle tree is built starting from the leaves.
-        for d/*: &[u8] */ in data.into_iter().rev() {
-            let _z: &'a[u8] = d;
+    pub fn from_data_with_scheme<T: AsRef<[u8]>>(data: &[T], scheme: HashScheme) -> Result<MerkleTree, MerkleError> {
+        MerkleTree::build(data, scheme, Layout::Balanced)
+    }
 
-            let leaf = MerkleTree::make_leaf(d);
-            nodes_map.insert(leaf.label.clone(), leaf.clone());
-            nodes_stack.push(leaf);
+    /**
+     * Proper and complete binary tree: with n leaves and k = floor(log2(n)),
+     * the first 2 * (n - 2^k) leaves are at depth k+1 and the others at depth k.
+     * Leaf depth and proof length only depend on the leaf index and count,
+     * see complete_leaf_depth.
+     */
+    pub fn complete_from_data<T: AsRef<[u8]>>(data: &[T]) -> Result<MerkleTree, MerkleError> {
+        MerkleTree::build(data, HashScheme::default(), Layout::Complete)
+    }
+
+    /**
+     * Same tree as from_data, hashed on all cores, see par_build.
+     */
+    pub fn par_from_data<T: AsRef<[u8]> + Sync>(data: &[T]) -> Result<MerkleTree, MerkleError> {
+        MerkleTree::par_build(data, HashScheme::default(), Layout::Balanced)
+    }
+}
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /**
+     * Builds the tree with any hash function,
+     * e.g. `MerkleTree::<Keccak256>::build(&data, HashScheme::Rfc6962, Layout::Balanced)`.
+     */
+    pub fn build<T: AsRef<[u8]>>(data: &[T], scheme: HashScheme, layout: Layout) -> Result<Self, MerkleError> {
+        if data.is_empty() {
+            return Err(MerkleError::EmptyInput);
         }
 
-        // process current level nodes to build nodes for the upper level.
-        while nodes_stack.len() > 1 {
-            let mut parents: Vec<Rc<Node<'a>>> = Vec::new(); // New nodes for the upper level
+        // The binary Merkle tree is built starting from the leaves.
+        let leaves = data.iter().map(|d| scheme.hash_leaf::<H>(d.as_ref())).collect();
+
+        Ok(MerkleTree::from_leaves(leaves, scheme, layout))
+    }
+
+    /**
+     * Tree over already hashed, non-empty leaves.
+     */
+    fn from_leaves(mut digests: Vec<Hash>, scheme: HashScheme, layout: Layout) -> Self {
+        let leaf_count = digests.len();
+        let pairs = level_pairs(layout, leaf_count);
+        let offsets = level_offsets(&pairs, leaf_count);
+        digests.reserve_exact(*offsets.last().unwrap() - leaf_count);
 
-            // Builds parent nodes
-            while nodes_stack.len() > 1 {
-                let child_left = nodes_stack.pop().unwrap();
-                let child_right = nodes_stack.pop().unwrap(); // nodes.len() > 1
+        // process current level nodes to build nodes for the upper level.
+        for (level, level_pairs) in pairs.iter().enumerate() {
+            let (start, end) = (offsets[level], offsets[level + 1]);
+            let carried_start = start + 2 * level_pairs;
 
-                let b = MerkleTree::make_branch(child_left, child_right); // moves ownership of children
-                nodes_map.insert(b.label.clone(), b.clone());
-                parents.insert(0, b);
+            for pair in (start..carried_start).step_by(2) {
+                let branch = scheme.hash_branch::<H>(&digests[pair], &digests[pair + 1]);
+                digests.push(branch);
             }
 
-            if let Some(n) = nodes_stack.pop() {
-                parents.insert(0, n); // odd number of nodes, moves the remaining node to the right of the tree
+            // nodes left out of pairs move up unchanged
+            for carried in carried_start..end {
+                let carried = digests[carried];
+                digests.push(carried);
             }
+        }
 
-            nodes_stack = parents;
+        MerkleTree{
+            digests,
+            offsets,
+            pairs,
+            leaf_count,
+            scheme,
+            layout,
+            hasher: PhantomData,
         }
+    }
 
-        assert_eq!(nodes_stack.len(), 1);
-        let tree = MerkleTree{
-            root: nodes_stack.pop().unwrap_or_else(|| panic!("Empty merkle tree!?")).clone(),
-            nodes: nodes_map
-        };
+    /**
+     * Builds the same tree as build, digest for digest, hashing the leaves and
+     * then each level in parallel on the rayon thread pool.
+     * The nodes of a level only depend on the level below, so every level is
+     * split in chunks of at least PAR_MIN_LEN nodes written in place.
+     */
+    pub fn par_build<T: AsRef<[u8]> + Sync>(data: &[T], scheme: HashScheme, layout: Layout) -> Result<Self, MerkleError> {
+        if data.is_empty() {
+            return Err(MerkleError::EmptyInput);
+        }
+
+        let pairs = level_pairs(layout, data.len());
+        let offsets = level_offsets(&pairs, data.len());
+        let mut digests: Vec<Hash> = vec![[0u8; 32]; *offsets.last().unwrap()];
+
+        digests[..data.len()].par_iter_mut().with_min_len(PAR_MIN_LEN)
+            .zip(data)
+            .for_each(|(digest, d)| *digest = scheme.hash_leaf::<H>(d.as_ref()));
+
+        for (level, level_pairs) in pairs.iter().enumerate() {
+            let (lower, upper) = digests.split_at_mut(offsets[level + 1]);
+            let lower = &lower[offsets[level]..];
+            let (branches, carried) = upper[..lower.len() - level_pairs].split_at_mut(*level_pairs);
+
+            branches.par_iter_mut().with_min_len(PAR_MIN_LEN)
+                .enumerate()
+                .for_each(|(i, branch)| *branch = scheme.hash_branch::<H>(&lower[2 * i], &lower[2 * i + 1]));
+            carried.copy_from_slice(&lower[2 * level_pairs..]);
+        }
+
+        Ok(MerkleTree{
+            digests,
+            offsets,
+            pairs,
+            leaf_count: data.len(),
+            scheme,
+            layout,
+            hasher: PhantomData,
+        })
+    }
+
+    pub fn root(&self) -> &Hash {
+        self.digests.last().unwrap()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
 
-        tree
+    pub fn scheme(&self) -> HashScheme {
+        self.scheme
     }
 
-    // #[allow(dead_code)]
-    // pub fn complete_from_data(data: &[&'a[u8]]) -> MerkleTree<'a> {
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /**
+     * Number of levels above the leaves.
+     */
+    pub fn height(&self) -> usize {
+        self.offsets.len() - 2
+    }
+
+    pub fn leaf(&self, index: usize) -> Option<&Hash> {
+        self.level(0).get(index)
+    }
+
+    /**
+     * Node at `position` of `level`, the leaves being level 0 and the root level `height()`.
+     */
+    pub fn node(&self, level: usize, position: usize) -> Option<&Hash> {
+        match level <= self.height() {
+            true => self.level(level).get(position),
+            false => None,
+        }
+    }
 
-    // }
+    /**
+     * Indices of every leaf holding `data`, duplicates included.
+     */
+    pub fn positions(&self, data: &[u8]) -> Vec<usize> {
+        let hash = self.scheme.hash_leaf::<H>(data);
+
+        self.level(0).iter().enumerate()
+            .filter(|(_, leaf)| **leaf == hash)
+            .map(|(index, _)| index)
+            .collect()
+    }
 
     /**
+     * Proof for the leaf at `leaf_index`, so that duplicate data get distinct proofs.
      * [Auth0, Auth1, .., Auth(i)] where 0 <= i < Height
      * i.e. from the leaf's sibling to the root's children
      */
-    pub fn make_proof(&self, data: &'a [u8]) -> Result<Vec<String>, &'static str> {
-        let hash = MerkleTree::sha3_hex(data);
-
-        // let node = *self..
-        let leaf: &Rc<Node> = self.nodes.get(hash.as_str()).ok_or_else(|| "Provided data not included in the tree")?;
-
-        let mut node = leaf.clone();
-        let mut proof = Vec::new();
-        while let Some(parent) = node.clone().parent.borrow().upgrade() {
-            match &parent.r#type { // parent: Rc<Node>
-                NodeType::Branch{left: l, right: r} => {
-                    if Rc::ptr_eq(l, &node) {
-                        proof.push(r.label.clone());
-                    } else if Rc::ptr_eq(r, &node) {
-                        proof.push(l.label.clone());
+    pub fn make_proof(&self, leaf_index: usize) -> Result<Proof, MerkleError> {
+        if leaf_index >= self.leaf_count {
+            return Err(MerkleError::UnknownLeaf{index: leaf_index, leaf_count: self.leaf_count});
+        }
+
+        let mut path = Vec::with_capacity(self.height());
+        let mut position = leaf_index;
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            let nodes = self.level(level);
+
+            if position < 2 * pairs {
+                if position % 2 == 1 {
+                    path.push(ProofStep{side: Side::Left, hash: nodes[position - 1]});
+                } else {
+                    path.push(ProofStep{side: Side::Right, hash: nodes[position + 1]});
+                }
+                position /= 2;
+            } else {
+                position -= pairs;
+            }
+        }
+
+        Ok(Proof{
+            leaf_index,
+            leaf_count: self.leaf_count,
+            layout: self.layout,
+            path,
+        })
+    }
+
+    /**
+     * Batch proof for the leaves at `leaf_indices`, in any order and possibly repeated.
+     * The proof lists them sorted and deduplicated, the data must be verified in that order.
+     */
+    pub fn make_multiproof(&self, leaf_indices: &[usize]) -> Result<MultiProof, MerkleError> {
+        let mut positions = leaf_indices.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+
+        match positions.last() {
+            None => return Err(MerkleError::EmptyInput),
+            Some(&last) if last >= self.leaf_count => return Err(MerkleError::UnknownLeaf{index: last, leaf_count: self.leaf_count}),
+            _ => {}
+        }
+
+        let leaf_indices = positions.clone();
+        let mut hashes = Vec::new();
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            let nodes = self.level(level);
+            let mut upper = Vec::with_capacity(positions.len());
+
+            let mut i = 0;
+            while i < positions.len() {
+                let position = positions[i];
+                if position < 2 * pairs {
+                    if position % 2 == 0 && positions.get(i + 1) == Some(&(position + 1)) {
+                        i += 1; // the sibling is proven too
                     } else {
-                        panic!("Wrong parent<->children references");
+                        hashes.push(nodes[position ^ 1]);
                     }
+                    upper.push(position / 2);
+                } else {
+                    upper.push(position - pairs);
                 }
-                _ => ()
+                i += 1;
             }
 
-            node = parent;
+            positions = upper;
         }
 
-        // assert proof length = height - 1
-        return Ok(proof);
+        Ok(MultiProof{
+            leaf_indices,
+            leaf_count: self.leaf_count,
+            layout: self.layout,
+            hashes,
+        })
+    }
+
+    /**
+     * Puts the tree's nodes in `store` and commits its root. A branch is stored as
+     * BRANCH_PREFIX and its children's hashes, a leaf as LEAF_PREFIX alone since
+     * its data is not kept. Nodes already stored, e.g. those a previous version
+     * of the tree shares with this one, are not written again.
+     */
+    pub fn save<S: NodeStore>(&self, store: &mut S) -> Result<(), MerkleError> {
+        for leaf in self.level(0) {
+            store.put(leaf, &[LEAF_PREFIX])?;
+        }
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            let (children, branches) = (self.level(level), self.level(level + 1));
+            for (i, branch) in branches[..*pairs].iter().enumerate() {
+                let node = [&[BRANCH_PREFIX], &children[2 * i][..], &children[2 * i + 1][..]].concat();
+                store.put(branch, &node)?;
+            }
+        }
+
+        Ok(store.push_root(self.root())?)
+    }
+
+    /**
+     * Tree saved in `store` under `root`, with the scheme and layout it was built with.
+     * Every branch read is checked against its hash.
+     */
+    pub fn load<S: NodeStore>(store: &S, root: &Hash, scheme: HashScheme, layout: Layout) -> Result<Self, MerkleError> {
+        let mut leaves = Vec::new();
+
+        // Depth first, left child first, so leaves come in order
+        let mut pending = vec![*root];
+        while let Some(hash) = pending.pop() {
+            let node = store.get(&hash)?.ok_or(MerkleError::MissingNode(hash))?;
+            match node.first() {
+                Some(&LEAF_PREFIX) if node.len() == 1 => leaves.push(hash),
+                Some(&BRANCH_PREFIX) if node.len() == 65 => {
+                    let (left, right): (Hash, Hash) = (node[1..33].try_into().unwrap(), node[33..].try_into().unwrap());
+                    if scheme.hash_branch::<H>(&left, &right) != hash {
+                        return Err(MerkleError::CorruptNode(hash));
+                    }
+                    pending.push(right);
+                    pending.push(left);
+                }
+                _ => return Err(MerkleError::CorruptNode(hash)),
+            }
+        }
+
+        // Saved with another layout, the leaves do not hash up to the same root
+        let tree = MerkleTree::from_leaves(leaves, scheme, layout);
+        match tree.root() == root {
+            true => Ok(tree),
+            false => Err(MerkleError::IncompatibleTrees),
+        }
+    }
+
+    /**
+     * Replaces the leaf at `leaf_index` and re-hashes only its path to the root: O(log n).
+     * Proofs made afterwards, for any leaf, are against the new root.
+     */
+    pub fn update_leaf(&mut self, leaf_index: usize, data: &[u8]) -> Result<(), MerkleError> {
+        if leaf_index >= self.leaf_count {
+            return Err(MerkleError::UnknownLeaf{index: leaf_index, leaf_count: self.leaf_count});
+        }
+
+        let mut hash = self.scheme.hash_leaf::<H>(data);
+        let mut position = leaf_index;
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            let offset = self.offsets[level];
+            self.digests[offset + position] = hash;
+
+            if position < 2 * pairs {
+                let left = offset + position - position % 2;
+                hash = self.scheme.hash_branch::<H>(&self.digests[left], &self.digests[left + 1]);
+                position /= 2;
+            } else {
+                position -= pairs; // carried nodes are copied unchanged
+            }
+        }
+
+        let root = self.offsets[self.height()] + position;
+        self.digests[root] = hash;
+
+        Ok(())
+    }
+
+    /**
+     * Checks the proof against this tree's root.
+     * Unlike verify_proof, the data must also be the leaf at the proof's index.
+     */
+    pub fn authenticate(&self, data: &[u8], proof: &Proof) -> bool {
+        self.leaf(proof.leaf_index) == Some(&self.scheme.hash_leaf::<H>(data))
+            && verify_proof_with_scheme::<H>(self.scheme, self.root(), data, proof)
+    }
+
+    /**
+     * Sorted ranges of the leaf indices where the trees differ, including the
+     * leaves only the larger tree has. Identical subtrees are skipped by hash,
+     * so the cost is O(d log n) for d differences.
+     *
+     * Trees of the same shape are descended node by node. Balanced trees of
+     * different sizes share the perfect subtrees over aligned ranges of leaves
+     * (RFC 6962), those are compared instead. Other trees cannot be compared.
+     */
+    pub fn diff(&self, other: &MerkleTree<H>) -> Result<Vec<Range<usize>>, MerkleError> {
+        if self.scheme != other.scheme {
+            return Err(MerkleError::IncompatibleTrees);
+        }
+
+        let mut ranges = Vec::new();
+        if self.leaf_count == other.leaf_count && self.layout == other.layout {
+            self.diff_nodes(other, self.height(), 0, &mut ranges);
+        } else if self.layout == Layout::Balanced && other.layout == Layout::Balanced {
+            let leaf_count = self.leaf_count.max(other.leaf_count);
+            let height = leaf_count.next_power_of_two().trailing_zeros() as usize;
+            self.diff_aligned(other, height, 0, &mut ranges);
+        } else {
+            return Err(MerkleError::IncompatibleTrees);
+        }
+
+        Ok(ranges)
+    }
+
+    /**
+     * Version, tag, scheme, layout, leaf count (u64 BE), then every digest
+     * level by level as stored, leaves first and root last.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + 32 * self.digests.len());
+        bytes.extend_from_slice(&[ENCODING_VERSION, TREE_TAG, encode_scheme(self.scheme), encode_layout(self.layout)]);
+        bytes.extend_from_slice(&(self.leaf_count as u64).to_be_bytes());
+        for digest in &self.digests {
+            bytes.extend_from_slice(digest);
+        }
+
+        bytes
+    }
+
+    /**
+     * Decodes a tree encoded by to_bytes with the same hash function.
+     * The digests are checked against each other, from the leaves up.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleError> {
+        let mut decoder = Decoder::new(bytes, TREE_TAG)?;
+        let scheme = decode_scheme(decoder.byte()?)?;
+        let layout = decode_layout(decoder.byte()?)?;
+        let leaf_count = decoder.usize()?;
+
+        let hashes = decoder.remaining() / 32;
+        let mut digests = Vec::with_capacity(hashes);
+        for _ in 0..hashes {
+            digests.push(decoder.hash()?);
+        }
+        decoder.finish()?;
+
+        MerkleTree::from_digests(digests, leaf_count, scheme, layout)
+    }
+
+    /**
+     * Tree of the given digests, if they are those of its shape
+     * and every branch is the hash of its children.
+     */
+    fn from_digests(digests: Vec<Hash>, leaf_count: usize, scheme: HashScheme, layout: Layout) -> Result<Self, MerkleError> {
+        if leaf_count == 0 || leaf_count > digests.len() {
+            return Err(MerkleError::InvalidEncoding);
+        }
+        let pairs = level_pairs(layout, leaf_count);
+        let offsets = level_offsets(&pairs, leaf_count);
+        if digests.len() != *offsets.last().unwrap() {
+            return Err(MerkleError::InvalidEncoding);
+        }
+
+        for (level, level_pairs) in pairs.iter().enumerate() {
+            let (lower, upper) = (&digests[offsets[level]..offsets[level + 1]], &digests[offsets[level + 1]..offsets[level + 2]]);
+            let (branches, carried) = upper.split_at(*level_pairs);
+
+            let hashed = branches.iter().enumerate()
+                .all(|(i, branch)| *branch == scheme.hash_branch::<H>(&lower[2 * i], &lower[2 * i + 1]));
+            if !hashed || carried != &lower[2 * level_pairs..] {
+                return Err(MerkleError::InvalidEncoding);
+            }
+        }
+
+        Ok(MerkleTree{
+            digests,
+            offsets,
+            pairs,
+            leaf_count,
+            scheme,
+            layout,
+            hasher: PhantomData,
+        })
+    }
+
+    /**
+     * Diff below the node at `position` of `level`, in trees of the same shape.
+     */
+    fn diff_nodes(&self, other: &MerkleTree<H>, level: usize, position: usize, ranges: &mut Vec<Range<usize>>) {
+        if self.level(level)[position] == other.level(level)[position] {
+            return;
+        }
+        if level == 0 {
+            push_range(ranges, position..position + 1);
+            return;
+        }
+
+        let pairs = self.pairs[level - 1];
+        if position < pairs {
+            self.diff_nodes(other, level - 1, 2 * position, ranges);
+            self.diff_nodes(other, level - 1, 2 * position + 1, ranges);
+        } else {
+            self.diff_nodes(other, level - 1, position + pairs, ranges); // carried up unchanged
+        }
+    }
+
+    /**
+     * Diff of the leaves [start, start + 2^level), in balanced trees of any size.
+     */
+    fn diff_aligned(&self, other: &MerkleTree<H>, level: usize, start: usize, ranges: &mut Vec<Range<usize>>) {
+        let end = start + (1 << level);
+        let shared = self.leaf_count.min(other.leaf_count);
+        let leaf_count = self.leaf_count.max(other.leaf_count);
+
+        if start >= leaf_count {
+            return;
+        }
+        if start >= shared {
+            push_range(ranges, start..end.min(leaf_count));
+            return;
+        }
+        if end <= shared {
+            // A perfect subtree in both trees
+            let position = start >> level;
+            if self.level(level)[position] == other.level(level)[position] {
+                return;
+            }
+            if level == 0 {
+                push_range(ranges, start..end);
+                return;
+            }
+        }
+
+        let half = 1 << (level - 1);
+        self.diff_aligned(other, level - 1, start, ranges);
+        self.diff_aligned(other, level - 1, start + half, ranges);
+    }
+
+    fn level(&self, level: usize) -> &[Hash] {
+        &self.digests[self.offsets[level]..self.offsets[level + 1]]
+    }
+
+    #[allow(dead_code)]
+    fn leaf_depth(&self, index: usize) -> usize {
+        path_sides(self.layout, index, self.leaf_count).map_or(0, |sides| sides.len())
+    }
+
+    #[allow(dead_code)]
+    fn count_branches(&self) -> usize {
+        self.pairs.iter().sum()
+    }
+}
+
+/**
+ * Recomputes the path from the leaf up to the root, hash by hash.
+ * Only the published root is needed, not the tree.
+ *
+ * The sides must also match the position of `leaf_index` in a tree of
+ * `leaf_count` leaves, so a proof cannot claim a different index.
+ */
+pub fn verify_proof(root: &Hash, data: &[u8], proof: &Proof) -> bool {
+    verify_proof_with_scheme::<Sha3_256>(HashScheme::default(), root, data, proof)
+}
+
+/**
+ * The hash function and scheme are chosen by the verifier, never taken from the proof.
+ */
+pub fn verify_proof_with_scheme<H: MerkleHasher>(scheme: HashScheme, root: &Hash, data: &[u8], proof: &Proof) -> bool {
+    match path_sides(proof.layout, proof.leaf_index, proof.leaf_count) {
+        Some(sides) if sides.len() == proof.path.len() => {
+            if sides.iter().zip(&proof.path).any(|(side, step)| *side != step.side) {
+                return false;
+            }
+        }
+        _ => return false,
+    }
+
+    let mut hash = scheme.hash_leaf::<H>(data);
+
+    for step in &proof.path {
+        hash = match step.side {
+            Side::Left => scheme.hash_branch::<H>(&step.hash, &hash),
+            Side::Right => scheme.hash_branch::<H>(&hash, &step.hash),
+        };
+    }
+
+    hash == *root
+}
+
+/**
+ * Recomputes the root from all the proven leaves at once.
+ * `data[i]` is the leaf at `proof.leaf_indices[i]`.
+ */
+pub fn verify_multiproof<T: AsRef<[u8]>>(root: &Hash, data: &[T], proof: &MultiProof) -> bool {
+    verify_multiproof_with_scheme::<Sha3_256, T>(HashScheme::default(), root, data, proof)
+}
+
+/**
+ * Every hash of the proof must be used, and the indices must be sorted, distinct and in range.
+ */
+pub fn verify_multiproof_with_scheme<H: MerkleHasher, T: AsRef<[u8]>>(scheme: HashScheme, root: &Hash, data: &[T], proof: &MultiProof) -> bool {
+    let indices = &proof.leaf_indices;
+    match indices.last() {
+        Some(&last) if last < proof.leaf_count && indices.len() == data.len() => {}
+        _ => return false,
+    }
+    if indices.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return false;
+    }
+
+    let mut nodes: Vec<(usize, Hash)> = indices.iter()
+        .zip(data)
+        .map(|(&index, datum)| (index, scheme.hash_leaf::<H>(datum.as_ref())))
+        .collect();
+    let mut hashes = proof.hashes.iter();
+
+    for pairs in level_pairs(proof.layout, proof.leaf_count) {
+        let mut upper = Vec::with_capacity(nodes.len());
+
+        let mut i = 0;
+        while i < nodes.len() {
+            let (position, hash) = nodes[i];
+            if position < 2 * pairs {
+                let sibling = match nodes.get(i + 1) {
+                    Some(&(next, next_hash)) if position % 2 == 0 && next == position + 1 => {
+                        i += 1;
+                        next_hash
+                    }
+                    _ => match hashes.next() {
+                        Some(sibling) => *sibling,
+                        None => return false,
+                    },
+                };
+                let branch = match position % 2 {
+                    1 => scheme.hash_branch::<H>(&sibling, &hash),
+                    _ => scheme.hash_branch::<H>(&hash, &sibling),
+                };
+                upper.push((position / 2, branch));
+            } else {
+                upper.push((position - pairs, hash));
+            }
+            i += 1;
+        }
+
+        nodes = upper;
+    }
+
+    hashes.next().is_none() && nodes == [(0, *root)]
+}
+
+/**
+ * Version, tag and the 32 bytes of the root.
+ */
+pub fn encode_root(root: &Hash) -> Vec<u8> {
+    [&[ENCODING_VERSION, ROOT_TAG][..], root].concat()
+}
+
+pub fn decode_root(bytes: &[u8]) -> Result<Hash, MerkleError> {
+    let mut decoder = Decoder::new(bytes, ROOT_TAG)?;
+    let root = decoder.hash()?;
+    decoder.finish()?;
+
+    Ok(root)
+}
+
+/**
+ * Reads a binary encoding, any read past its end is an InvalidEncoding.
+ */
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /**
+     * Checks the version and the tag of the encoding.
+     */
+    fn new(bytes: &'a [u8], tag: u8) -> Result<Decoder<'a>, MerkleError> {
+        let mut decoder = Decoder{bytes};
+        match (decoder.byte()?, decoder.byte()?) {
+            (ENCODING_VERSION, found) if found == tag => Ok(decoder),
+            _ => Err(MerkleError::InvalidEncoding),
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], MerkleError> {
+        if self.bytes.len() < length {
+            return Err(MerkleError::InvalidEncoding);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, MerkleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize, MerkleError> {
+        let value = u64::from_be_bytes(self.take(8)?.try_into().unwrap());
+        value.try_into().map_err(|_| MerkleError::InvalidEncoding)
+    }
+
+    fn hash(&mut self) -> Result<Hash, MerkleError> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len()
     }
 
     /**
-     * Problem:
-     * the current method requires knowledge of the tree structure
-     * in order to properly concatenate hashes (left||right !== right||left)s
+     * Trailing bytes are rejected too.
      */
-    pub fn authenticate(&self, data: &'a [u8], proof: &[String]) -> bool {
-        let mut node = match self.nodes.get(&MerkleTree::make_label(data)) {
-            Some(n) => n.clone(),
-            None => return false
-        };
-
-        for auth in proof {
-            let parent = match node.parent.borrow().upgrade() {
-                Some(n) => n,
-                None => return false // every proof's hash must have a parent. Root is not included in proofs.
-            };
-
-            let sibling: &Rc<Node<'a>> = match &parent.r#type {
-                NodeType::Branch{left: l, right: r} => {
-                    match &l.label == auth {
-                        true => l,
-                        false => r,
-                    }
-                }
-                _ => panic!("Parent must have children!")
-            };
+    fn finish(self) -> Result<(), MerkleError> {
+        match self.bytes.is_empty() {
+            true => Ok(()),
+            false => Err(MerkleError::InvalidEncoding),
+        }
+    }
+}
 
-            if sibling.label != *auth {
-                return false;
-            }
+fn encode_scheme(scheme: HashScheme) -> u8 {
+    match scheme {
+        HashScheme::Rfc6962 => 0,
+        HashScheme::Legacy => 1,
+    }
+}
 
-            node = parent;
-        }
+fn decode_scheme(byte: u8) -> Result<HashScheme, MerkleError> {
+    match byte {
+        0 => Ok(HashScheme::Rfc6962),
+        1 => Ok(HashScheme::Legacy),
+        _ => Err(MerkleError::InvalidEncoding),
+    }
+}
 
-        true
+fn encode_layout(layout: Layout) -> u8 {
+    match layout {
+        Layout::Balanced => 0,
+        Layout::Complete => 1,
     }
+}
 
-    fn make_leaf(data: &'a [u8]) -> Rc<Node<'a>> { // 1st + 2nd lifetime elision rule???
-        Rc::new(Node{
-            r#type: NodeType::Leaf{ data: data },
-            label: MerkleTree::make_label(data),
-            parent: RefCell::new(Weak::new()),
-        })
+fn decode_layout(byte: u8) -> Result<Layout, MerkleError> {
+    match byte {
+        0 => Ok(Layout::Balanced),
+        1 => Ok(Layout::Complete),
+        _ => Err(MerkleError::InvalidEncoding),
     }
+}
 
-    fn make_branch(child_left: Rc<Node<'a>>, child_right: Rc<Node<'a>>) -> Rc<Node<'a>> {
-        let label: String = MerkleTree::make_label([
-            child_left.label.as_str(), child_right.label.as_str()
-        ].concat().as_bytes());
+/**
+ * Depth of the leaf at `index` in a complete tree of `count` leaves,
+ * which is also the length of its proof.
+ */
+pub fn complete_leaf_depth(index: usize, count: usize) -> Option<usize> {
+    if index >= count {
+        return None;
+    }
 
-        // Rc needed to be able to create a weak reference for its children
-        let branch = Rc::new(Node{
-            r#type: NodeType::Branch {
-                left: child_left,
-                right: child_right,
-            },
-            label: label,
-            parent: RefCell::new(Weak::new()),
-        });
+    let last_filled_level = log2_floor(count);
+    let leaves_on_last_level = 2 * (count - (1 << last_filled_level));
 
-        if let NodeType::Branch{left: l, right: r, ..} = &branch.r#type {
-            *l.parent.borrow_mut() = Rc::downgrade(&branch);
-            *r.parent.borrow_mut() = Rc::downgrade(&branch);
-        }
+    match index < leaves_on_last_level {
+        true => Some(last_filled_level + 1),
+        false => Some(last_filled_level),
+    }
+}
 
-        branch
+/**
+ * Appends the range, merged with the last one when contiguous.
+ */
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
     }
+}
 
-    #[allow(dead_code)]
-    fn node_depth(&self, node: &Rc<Node>) -> usize {
-        let mut depth = 0;
-        let mut node: Rc<Node> = node.clone();
+fn log2_floor(n: usize) -> usize {
+    (usize::MAX.count_ones() - 1 - n.leading_zeros()) as usize
+}
 
-        while let Some(parent) = node.clone().parent.borrow().upgrade() {
-            node = parent;
-            depth = depth + 1;
+/**
+ * Number of branches built from each level, from the leaves up.
+ */
+pub(crate) fn level_pairs(layout: Layout, leaf_count: usize) -> Vec<usize> {
+    let mut pairs = Vec::new();
+    let mut count = leaf_count;
+
+    if layout == Layout::Complete && count > 1 {
+        // Only the deepest leaves are paired, the upper levels form a perfect tree
+        let deepest = count - (1 << log2_floor(count));
+        if deepest > 0 {
+            pairs.push(deepest);
+            count -= deepest;
         }
-
-        depth
     }
 
-    #[allow(dead_code)]
-    fn count_nodes(&self) -> usize {
-        MerkleTree::count_descendants(&self.root)
+    while count > 1 {
+        pairs.push(count / 2);
+        count -= count / 2;
     }
 
-    #[allow(dead_code)]
-    fn count_leaves(&self) -> usize {
-        MerkleTree::count_descendant_leaves(&self.root)
-    }
+    pairs
+}
 
-    fn count_descendants(node: &Rc<Node>) -> usize {
-        1 + match &node.r#type {
-            NodeType::Branch{left: l, right: r, ..} => MerkleTree::count_descendants(l) + MerkleTree::count_descendants(r),
-            NodeType::Leaf{..} => 0
-        }
-    }
+/**
+ * Offsets of each level in the digests array.
+ * The last offset is the total number of digests.
+ */
+pub(crate) fn level_offsets(pairs: &[usize], leaf_count: usize) -> Vec<usize> {
+    let mut offsets = vec![0, leaf_count];
+    let mut count = leaf_count;
 
-    fn count_descendant_leaves(node: &Rc<Node>) -> usize {
-        match &node.r#type {
-            NodeType::Branch{left: l, right: r, ..} => MerkleTree::count_descendant_leaves(l) + MerkleTree::count_descendant_leaves(r),
-            NodeType::Leaf{..} => 1,
-        }
+    for level_pairs in pairs {
+        count -= level_pairs;
+        offsets.push(offsets.last().unwrap() + count);
     }
 
-    fn make_label(data: &[u8]) -> String {
-        MerkleTree::sha3_hex(data)
+    offsets
+}
+
+/**
+ * Sibling sides from the leaf at `index` up to the root: the first pairs of a
+ * level are hashed together, the nodes left out move up unchanged.
+ */
+pub(crate) fn path_sides(layout: Layout, index: usize, count: usize) -> Option<Vec<Side>> {
+    if index >= count {
+        return None;
     }
 
-    fn sha3_hex(data: &[u8]) -> String {
-        hex_string(Sha3_256::digest(data).as_slice()).unwrap()
+    let mut sides = Vec::new();
+    let mut position = index;
+    for pairs in level_pairs(layout, count) {
+        if position < 2 * pairs {
+            match position % 2 {
+                1 => sides.push(Side::Left),
+                _ => sides.push(Side::Right),
+            }
+            position /= 2;
+        } else {
+            position -= pairs;
+        }
     }
+
+    Some(sides)
 }
 
 // Merkle Root represent a version of the state
@@ -266,6 +1271,8 @@ impl<'a> MerkleTree<'a> {
 mod tests {
     use super::*; // includes private functions
     use rand::{thread_rng, Rng};
+    use crate::nodestore::{MemoryStore, FileStore};
+    use crate::nodestore::tests::TestDir;
 
     fn make_data(amount: usize) -> Vec<Vec<u8>> {
         let mut data: Vec<Vec<u8>> = Vec::new();
@@ -277,16 +1284,86 @@ mod tests {
         data
     }
 
-    fn make_data_refs<'a>(data: &'a Vec<Vec<u8>>) -> Vec<&'a [u8]> {
-        let refs: Vec<&[u8]> = data.iter().map(|d| d.as_slice()).collect();
+    fn make_data_refs(data: &[Vec<u8>]) -> Vec<&[u8]> {
+        data.iter().map(|d| d.as_slice()).collect()
+    }
 
-        refs
+    fn hex(hash: &str) -> Hash {
+        from_hex(hash).unwrap()
     }
 
     #[test]
     fn sha3_hash() {
         let data = "Some random data".as_bytes();
         assert_eq!(hex_string(Sha3_256::digest(data).as_slice()).unwrap(), "5b054cb1c47ebc3e0bd156e474a36ab2068807eb14bbe609639fc1f9bf53261a");
+        assert_eq!(to_hex(&HashScheme::Legacy.hash_leaf::<Sha3_256>(data)), "5b054cb1c47ebc3e0bd156e474a36ab2068807eb14bbe609639fc1f9bf53261a");
+        assert_ne!(HashScheme::Rfc6962.hash_leaf::<Sha3_256>(data), HashScheme::Legacy.hash_leaf::<Sha3_256>(data));
+    }
+
+    fn check_hasher<H: MerkleHasher>(abc: &str) {
+        assert_eq!(to_hex(&H::hash(&[b"abc"])), abc);
+        assert_eq!(H::hash(&[b"a", b"", b"bc"]), H::hash(&[b"abc"]));
+
+        for leaves_count in 1..=17 {
+            let data: Vec<Vec<u8>> = make_data(leaves_count);
+            let tree = MerkleTree::<H>::build(&data, HashScheme::Rfc6962, Layout::Balanced).unwrap();
+
+            for (i, datum) in data.iter().enumerate() {
+                let proof = tree.make_proof(i).unwrap();
+                assert!(tree.authenticate(datum, &proof));
+                assert!(verify_proof_with_scheme::<H>(HashScheme::Rfc6962, tree.root(), datum, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn hashers() {
+        check_hasher::<Sha3_256>("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+        check_hasher::<Keccak256>("4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45");
+        check_hasher::<Sha256>("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        check_hasher::<Blake2b256>("bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319");
+
+        // Different hash functions, different roots
+        let data = make_data(5);
+        let sha3 = MerkleTree::from_data(&data).unwrap();
+        let keccak = MerkleTree::<Keccak256>::build(&data, HashScheme::Rfc6962, Layout::Balanced).unwrap();
+        assert_ne!(sha3.root(), keccak.root());
+        let proof = keccak.make_proof(2).unwrap();
+        assert!(!verify_proof(keccak.root(), &data[2], &proof));
+    }
+
+    #[test]
+    fn certificate_transparency_roots() {
+        // RFC 6962 test vectors, from the Certificate Transparency reference implementation
+        let leaves: [&[u8]; 8] = [
+            b"", b"\x00", b"\x10", b"\x20\x21", b"\x30\x31", b"\x40\x41\x42\x43",
+            b"\x50\x51\x52\x53\x54\x55\x56\x57",
+            b"\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f",
+        ];
+        let roots = [
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+            "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+            "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+            "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+            "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+            "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+            "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+        ];
+
+        for (size, root) in roots.iter().enumerate() {
+            let tree = MerkleTree::<Sha256>::build(&leaves[..size + 1], HashScheme::Rfc6962, Layout::Balanced).unwrap();
+            assert_eq!(to_hex(tree.root()), *root);
+        }
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let hash = HashScheme::default().hash_leaf::<Sha3_256>(b"hex");
+        assert_eq!(from_hex(&to_hex(&hash)), Some(hash));
+        assert_eq!(from_hex("00"), None);
+        assert_eq!(from_hex(&[to_hex(&hash), "00".to_string()].concat()), None);
+        assert_eq!(from_hex(&"zz".repeat(32)), None);
     }
 
     #[test]
@@ -296,114 +1373,635 @@ mod tests {
             let data: Vec<Vec<u8>> = make_data(leaves_count);
             let data_refs: Vec<&[u8]> = make_data_refs(&data);
 
-            let tree = MerkleTree::from_data(&data_refs);
-            let leaves = tree.count_leaves();
-            let branches = tree.count_nodes() - leaves;
+            let tree = MerkleTree::from_data(&data_refs).unwrap();
+            let leaves = tree.leaf_count();
+            let branches = tree.count_branches();
             println!("Merkle tree leaves={} branches={} leaves-branches={}", leaves, branches, leaves-branches);
             assert_eq!(branches, leaves - 1);
         }
     }
 
+    #[test]
+    fn tree_layout() {
+        for leaves_count in 1..=97 {
+            let data: Vec<Vec<u8>> = make_data(leaves_count);
+            let tree = MerkleTree::from_data(&data).unwrap();
+
+            // Leaves first, one digest per leaf, root last
+            assert_eq!(tree.level(0).len(), leaves_count);
+            assert_eq!(tree.level(tree.height()).len(), 1);
+            assert_eq!(tree.height(), (leaves_count as f64).log2().ceil() as usize);
+            assert_eq!(tree.digests.len(), *tree.offsets.last().unwrap());
+            assert_eq!(tree.digests.capacity(), tree.digests.len());
+        }
+    }
+
+    #[test]
+    fn empty_input() {
+        let empty: Vec<Vec<u8>> = Vec::new();
+        assert!(matches!(MerkleTree::from_data(&empty), Err(MerkleError::EmptyInput)));
+        assert!(matches!(MerkleTree::complete_from_data(&empty), Err(MerkleError::EmptyInput)));
+        assert!(matches!(MerkleTree::par_from_data(&empty), Err(MerkleError::EmptyInput)));
+        assert_eq!(MerkleError::EmptyInput.to_string(), "Empty merkle tree");
+    }
+
+    #[test]
+    fn hostile_proofs() {
+        // Whatever a peer sends, the proof is rejected without panicking
+        let data = make_data(6);
+        let tree = MerkleTree::from_data(&data).unwrap();
+        let proof = tree.make_proof(3).unwrap();
+
+        for (leaf_index, leaf_count) in [(usize::MAX, usize::MAX), (usize::MAX - 1, usize::MAX), (0, 0), (7, 6)] {
+            for layout in [Layout::Balanced, Layout::Complete] {
+                let forged = Proof{leaf_index, leaf_count, layout, ..proof.clone()};
+                assert!(!verify_proof(tree.root(), &data[3], &forged));
+                assert!(!tree.authenticate(&data[3], &forged));
+
+                let multi = MultiProof{leaf_indices: vec![leaf_index], leaf_count, layout, hashes: vec![[0u8; 32]; 64]};
+                assert!(!verify_multiproof(tree.root(), &data[3..4], &multi));
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_build() {
+        let data: Vec<Vec<u8>> = (0..5 * PAR_MIN_LEN as u32 + 3).map(|i| i.to_be_bytes().to_vec()).collect();
+
+        for layout in [Layout::Balanced, Layout::Complete].iter() {
+            for scheme in [HashScheme::Rfc6962, HashScheme::Legacy].iter() {
+                for leaves_count in 1..=70 {
+                    let tree = MerkleTree::<Sha3_256>::build(&data[..leaves_count], *scheme, *layout).unwrap();
+                    let parallel = MerkleTree::<Sha3_256>::par_build(&data[..leaves_count], *scheme, *layout).unwrap();
+                    assert_eq!(parallel.digests, tree.digests);
+                    assert_eq!(parallel.offsets, tree.offsets);
+                }
+            }
+
+            // Levels split across tasks
+            for leaves_count in [2 * PAR_MIN_LEN - 1, 2 * PAR_MIN_LEN, data.len()].iter() {
+                let tree = MerkleTree::<Sha3_256>::build(&data[..*leaves_count], HashScheme::Rfc6962, *layout).unwrap();
+                let parallel = MerkleTree::<Sha3_256>::par_build(&data[..*leaves_count], HashScheme::Rfc6962, *layout).unwrap();
+                assert_eq!(parallel.digests, tree.digests);
+            }
+        }
+
+        assert_eq!(MerkleTree::par_from_data(&data).unwrap().root(), MerkleTree::from_data(&data).unwrap().root());
+    }
+
     #[test]
     fn tree_leaf_depth() {
         let data: Vec<Vec<u8>> = make_data(11);
         let data_refs: Vec<&[u8]> = make_data_refs(&data);
-        let tree = MerkleTree::from_data(&data_refs);
-
-        let rightmost = dbg!(tree.nodes.get("8bf02b8b238233453488311be9b316e58ab7e1356ce948cb90dfef1af56992eb").unwrap()); //9
-        let leftmost = dbg!(tree.nodes.get("2767f15c8af2f2c7225d5273fdd683edc714110a987d1054697c348aed4e6cc7").unwrap()); //1
-        let center = dbg!(tree.nodes.get("989216075a288af2c12f115557518d248f93c434965513f5f739df8c9d6e1932").unwrap()); // 4
-        dbg!(tree.node_depth(rightmost));
-        dbg!(tree.node_depth(leftmost));
-        dbg!(tree.node_depth(center));
-
-        assert_eq!(tree.node_depth(&tree.root), 0);
-
-        match &tree.root.r#type {
-            // root
-            NodeType::Branch{left: l, right: r} => {
-                assert_eq!(tree.node_depth(r), 1);
-                assert_eq!(tree.node_depth(l), 1);
-
-                match &l.r#type {
-                    // root -> left
-                    NodeType::Branch{left: l_l, right: l_r, ..} => {
-                        assert_eq!(tree.node_depth(l_l), 2);
-                        assert_eq!(tree.node_depth(l_r), 2);
-
-                        match &l_l.r#type {
-                            // root -> left -> left
-                            NodeType::Branch{left: l_l_l, right: l_l_r, .. } => {
-                                assert_eq!(tree.node_depth(l_l_l), 3);
-                                assert_eq!(tree.node_depth(l_l_r), 3);
-                            },
-                            _ => panic!("Unexpected node type"),
-                        }
-                    }
-                    _ => panic!("Unexpected node type"),
-                }
-
-                match &r.r#type {
-                    // root -> right
-                    NodeType::Branch{left: r_l, right: r_r} => {
-                        assert_eq!(tree.node_depth(r_l), 2);
-                        assert_eq!(tree.node_depth(r_r), 2);
-
-                        match &r_r.r#type {
-                            // root -> right -> right
-                            NodeType::Leaf{..} => assert_eq!(tree.node_depth(&r_r), 2),
-                            _ => panic!("Unexpected root type NodeType::Leaf"),
-                        }
-                    },
-                    _ => panic!("Unexpected node type"),
-                }
+        let tree = MerkleTree::from_data(&data_refs).unwrap();
 
-            },
-            _ => panic!("Unexpected root type NodeType::Leaf"),
-        };
+        // Level sizes 11, 6, 3, 2, 1
+        assert_eq!(tree.height(), 4);
+        for leftmost in 0..8 {
+            assert_eq!(tree.leaf_depth(leftmost), 4);
+        }
+        assert_eq!(tree.leaf_depth(8), 3);
+        assert_eq!(tree.leaf_depth(9), 3);
+        // root -> right -> right
+        assert_eq!(tree.leaf_depth(10), 2);
+
+        let root = tree.level(4)[0];
+        let (l, r) = (tree.level(3)[0], tree.level(3)[1]);
+        assert_eq!(root, tree.scheme().hash_branch::<Sha3_256>(&l, &r));
+        // The odd node out of level 2 is copied unchanged to level 3
+        assert_eq!(r, tree.scheme().hash_branch::<Sha3_256>(&tree.level(1)[4], &tree.level(1)[5]));
+        assert_eq!(tree.level(1)[5], tree.level(0)[10]);
     }
 
     #[test]
     fn data_proofs() {
         let data: Vec<Vec<u8>> = make_data(5);
         let data_refs: Vec<&[u8]> = make_data_refs(&data);
-        let tree = MerkleTree::from_data(&data_refs);
+        let tree = MerkleTree::from_data_with_scheme(&data_refs, HashScheme::Legacy).unwrap();
 
         let datum: &[u8] = data_refs[4];
-        let proof = tree.make_proof(datum).unwrap();
-        assert_eq!(proof, [
-            "5ea0ffd548dacbbfc23452a271a0fab46f39114bda991ce85bf0386ca2294d3f"
+        let proof = tree.make_proof(4).unwrap();
+        assert_eq!(proof.leaf_index, 4);
+        assert_eq!(proof.leaf_count, 5);
+        assert_eq!(proof.path, [
+            ProofStep{side: Side::Left, hash: hex("5ea0ffd548dacbbfc23452a271a0fab46f39114bda991ce85bf0386ca2294d3f")}
         ]);
         assert!(tree.authenticate(datum, &proof));
 
         let datum: &[u8] = data_refs[3];
-        let proof = tree.make_proof(datum).unwrap();
-        assert_eq!(proof, [
-            "e3ed56bd086d8958483a12734fa0ae7f5c8bb160ef9092c67e82ed9b19e4c7b2",
-            "bfec02f100e0803e2124e5c28a567ccc5547640e96aa1ca3ed8798ba21d2e1ab",
-            "3b0c4d506212cd7e7b88bc93b5b1811ab5de6796d2780e9de7378c87fe9a80a6"
+        let proof = tree.make_proof(3).unwrap();
+        assert_eq!(proof.leaf_index, 3);
+        assert_eq!(proof.path, [
+            ProofStep{side: Side::Left, hash: hex("e3ed56bd086d8958483a12734fa0ae7f5c8bb160ef9092c67e82ed9b19e4c7b2")},
+            ProofStep{side: Side::Left, hash: hex("bfec02f100e0803e2124e5c28a567ccc5547640e96aa1ca3ed8798ba21d2e1ab")},
+            ProofStep{side: Side::Right, hash: hex("3b0c4d506212cd7e7b88bc93b5b1811ab5de6796d2780e9de7378c87fe9a80a6")}
         ]);
         assert!(tree.authenticate(datum, &proof));
+        assert!(!tree.authenticate(data_refs[2], &proof));
+
+        let index = thread_rng().gen_range(0u8, 5u8) as usize;
+        let datum: &[u8] = data_refs[index];
+        let proof = tree.make_proof(index).unwrap();
+        assert!(tree.authenticate(datum, &proof), "Invalid proof for {:?}", datum);
+
+        assert!(matches!(tree.make_proof(5), Err(MerkleError::UnknownLeaf{index: 5, leaf_count: 5})));
+        assert!(tree.positions(&[6u8]).is_empty());
+
+        // Legacy proofs do not verify with the default scheme
+        assert!(verify_proof_with_scheme::<Sha3_256>(HashScheme::Legacy, tree.root(), datum, &proof));
+        assert!(!verify_proof(tree.root(), datum, &proof));
+    }
+
+    #[test]
+    fn legacy_roots() {
+        // Roots of the first trees, which hashed branches over their children's hex labels
+        let roots = [
+            (1, "2767f15c8af2f2c7225d5273fdd683edc714110a987d1054697c348aed4e6cc7"),
+            (2, "bfec02f100e0803e2124e5c28a567ccc5547640e96aa1ca3ed8798ba21d2e1ab"),
+            (5, "a6910d3fb99eb966d1af1814cecf2626e0dddaa324d57f61b2ae47003249803a"),
+            (9, "e2949a9e1b1a4ae937c2d83dab56fc4aff52f565c5ffdbaeb7782a21172a3dd8"),
+            (13, "fd83e87720667bb451fa28af09289a3c03a88faddcf2075edd13a99ab9b764ed"),
+        ];
+
+        for (leaves_count, root) in roots.iter() {
+            let tree = MerkleTree::from_data_with_scheme(&make_data(*leaves_count), HashScheme::Legacy).unwrap();
+            assert_eq!(to_hex(tree.root()), *root);
+        }
+    }
+
+    #[test]
+    fn leaf_branch_domain_separation() {
+        let data: Vec<Vec<u8>> = make_data(4);
+
+        for scheme in [HashScheme::Legacy, HashScheme::Rfc6962].iter().cloned() {
+            let tree = MerkleTree::from_data_with_scheme(&data, scheme).unwrap();
+
+            // Present the children of the root's left branch as the data of a leaf in a 2 leaves tree
+            let forged_leaf = match scheme {
+                HashScheme::Legacy => [to_hex(&tree.level(0)[0]), to_hex(&tree.level(0)[1])].concat().into_bytes(),
+                HashScheme::Rfc6962 => [tree.level(0)[0], tree.level(0)[1]].concat(),
+            };
+            let forged_proof = Proof{
+                leaf_index: 0,
+                leaf_count: 2,
+                layout: Layout::Balanced,
+                path: vec![ProofStep{side: Side::Right, hash: tree.level(1)[1]}],
+            };
+
+            let accepted = verify_proof_with_scheme::<Sha3_256>(scheme, tree.root(), &forged_leaf, &forged_proof);
+            assert_eq!(accepted, scheme == HashScheme::Legacy);
+        }
+    }
+
+    #[test]
+    fn duplicate_leaves() {
+        // Two meters reporting the same reading
+        let data: Vec<&[u8]> = vec![b"10kWh", b"7kWh", b"10kWh", b"3kWh", b"10kWh"];
+        let tree = MerkleTree::from_data(&data).unwrap();
+
+        assert_eq!(tree.positions(b"10kWh"), vec![0, 2, 4]);
+        assert_eq!(tree.positions(b"7kWh"), vec![1]);
+        assert!(tree.positions(b"1kWh").is_empty());
+
+        let proofs: Vec<Proof> = tree.positions(b"10kWh").into_iter()
+            .map(|index| tree.make_proof(index).unwrap())
+            .collect();
+        for (i, proof) in proofs.iter().enumerate() {
+            assert!(tree.authenticate(b"10kWh", proof));
+            assert!(verify_proof(tree.root(), b"10kWh", proof));
+            for other in &proofs[i + 1..] {
+                assert_ne!(proof, other);
+            }
+        }
+
+        // A proof for one position does not authenticate a different datum
+        assert!(!tree.authenticate(b"7kWh", &proofs[0]));
+    }
+
+    #[test]
+    fn update_leaves() {
+        for layout in [Layout::Balanced, Layout::Complete].iter().cloned() {
+            for leaves_count in 1..=23 {
+                let mut data: Vec<Vec<u8>> = make_data(leaves_count);
+                let mut tree = MerkleTree::<Sha3_256>::build(&data, HashScheme::default(), layout).unwrap();
+
+                for index in 0..leaves_count {
+                    let old_root = *tree.root();
+                    data[index] = format!("Updated reading {}", index).into_bytes();
+                    tree.update_leaf(index, &data[index]).unwrap();
+                    assert_ne!(*tree.root(), old_root);
+
+                    // Same digests as a rebuild, so proofs of all leaves match the new root
+                    let rebuilt = MerkleTree::<Sha3_256>::build(&data, HashScheme::default(), layout).unwrap();
+                    assert_eq!(tree.digests, rebuilt.digests);
+                    for (i, datum) in data.iter().enumerate() {
+                        assert!(tree.authenticate(datum, &tree.make_proof(i).unwrap()));
+                    }
+                }
+
+                assert!(tree.update_leaf(leaves_count, b"out of range").is_err());
+            }
+        }
 
-        let datum: &[u8] = data_refs[thread_rng().gen_range(0u8, 5u8) as usize];
-        let proof = tree.make_proof(datum).unwrap();
-        assert!(tree.authenticate(datum, &proof), format!("Invalid proof for {:?}", datum));
+        // Old proofs no longer verify against the new root
+        let data = make_data(6);
+        let mut tree = MerkleTree::from_data(&data).unwrap();
+        let proof = tree.make_proof(1).unwrap();
+        tree.update_leaf(4, b"new reading").unwrap();
+        assert!(!tree.authenticate(&data[1], &proof));
+        assert!(tree.authenticate(&data[1], &tree.make_proof(1).unwrap()));
+    }
+
+    #[test]
+    fn owned_tree_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MerkleTree>();
+
+        // The tree outlives the buffers it was built from
+        let tree = {
+            let data: Vec<Vec<u8>> = make_data(9);
+            MerkleTree::from_data(&data).unwrap()
+        };
+
+        let shared = std::sync::Arc::new(tree);
+        let handles: Vec<_> = (1..=9u8).map(|d| {
+            let tree = shared.clone();
+            std::thread::spawn(move || {
+                let proof = tree.make_proof(d as usize - 1).unwrap();
+                verify_proof(tree.root(), &[d], &proof)
+            })
+        }).collect();
+
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn verify_against_root() {
+        let data: Vec<Vec<u8>> = make_data(11);
+        let data_refs: Vec<&[u8]> = make_data_refs(&data);
+        let tree = MerkleTree::from_data(&data_refs).unwrap();
+        let root = *tree.root();
+
+        for (i, datum) in data_refs.iter().enumerate() {
+            let proof = tree.make_proof(i).unwrap();
+            assert!(verify_proof(&root, datum, &proof));
+            assert!(!verify_proof(&root, &[42u8], &proof));
+        }
+
+        // Swapping a sibling's side changes the concatenation order
+        let mut proof = tree.make_proof(3).unwrap();
+        proof.path[0].side = match proof.path[0].side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        };
+        assert!(!verify_proof(&root, data_refs[3], &proof));
+
+        let single = MerkleTree::from_data(&data_refs[..1]).unwrap();
+        let proof = single.make_proof(0).unwrap();
+        assert!(proof.path.is_empty());
+        assert!(verify_proof(single.root(), data_refs[0], &proof));
+    }
+
+    #[test]
+    fn proof_leaf_index() {
+        for leaves_count in 1..=33 {
+            let data: Vec<Vec<u8>> = make_data(leaves_count);
+            let data_refs: Vec<&[u8]> = make_data_refs(&data);
+            let tree = MerkleTree::from_data(&data_refs).unwrap();
+
+            for (i, datum) in data_refs.iter().enumerate() {
+                let proof = tree.make_proof(i).unwrap();
+                assert_eq!(proof.leaf_index, i);
+                assert_eq!(proof.leaf_count, leaves_count);
+                assert_eq!(proof.path.len(), tree.leaf_depth(i));
+                assert!(verify_proof(tree.root(), datum, &proof));
+
+                // The index is bound to the path
+                let mut moved = proof.clone();
+                moved.leaf_index = (i + 1) % leaves_count;
+                if path_sides(Layout::Balanced, moved.leaf_index, leaves_count) != path_sides(Layout::Balanced, i, leaves_count) {
+                    assert!(!verify_proof(tree.root(), datum, &moved));
+                }
+
+                let mut out_of_range = proof.clone();
+                out_of_range.leaf_index = leaves_count;
+                assert!(!verify_proof(tree.root(), datum, &out_of_range));
+            }
+        }
+    }
+
+    #[test]
+    fn multiproofs() {
+        for layout in [Layout::Balanced, Layout::Complete].iter().cloned() {
+            for leaves_count in 1..=10 {
+                let data: Vec<Vec<u8>> = make_data(leaves_count);
+                let tree = MerkleTree::<Sha3_256>::build(&data, HashScheme::default(), layout).unwrap();
+
+                // Every non-empty subset of the leaves
+                for subset in 1..(1usize << leaves_count) {
+                    let indices: Vec<usize> = (0..leaves_count).filter(|i| subset & (1 << i) != 0).collect();
+                    let batch: Vec<&Vec<u8>> = indices.iter().map(|&i| &data[i]).collect();
+                    let proof = tree.make_multiproof(&indices).unwrap();
+                    assert_eq!(proof.leaf_indices, indices);
+                    assert!(verify_multiproof(tree.root(), &batch, &proof));
+
+                    // Never more hashes than the single proofs together
+                    let single: usize = indices.iter().map(|&i| tree.make_proof(i).unwrap().path.len()).sum();
+                    assert!(proof.hashes.len() <= single);
+                    if indices.len() == 1 {
+                        assert_eq!(proof.hashes.len(), single);
+                    }
+                    if indices.len() == leaves_count {
+                        assert!(proof.hashes.is_empty());
+                    }
+
+                    let mut tampered = batch.clone();
+                    let forged = b"forged".to_vec();
+                    tampered[0] = &forged;
+                    assert!(!verify_multiproof(tree.root(), &tampered, &proof));
+                }
+            }
+        }
+
+        let data: Vec<Vec<u8>> = make_data(11);
+        let tree = MerkleTree::from_data(&data).unwrap();
+
+        // Unsorted and repeated indices are normalized
+        let proof = tree.make_multiproof(&[7, 2, 7, 3]).unwrap();
+        assert_eq!(proof.leaf_indices, vec![2, 3, 7]);
+        let batch = [&data[2], &data[3], &data[7]];
+        assert!(verify_multiproof(tree.root(), &batch, &proof));
+
+        // Data in the wrong order, missing or extra hashes and bad indices are rejected
+        assert!(!verify_multiproof(tree.root(), &[&data[3], &data[2], &data[7]], &proof));
+        let mut extra = proof.clone();
+        extra.hashes.push(proof.hashes[0]);
+        assert!(!verify_multiproof(tree.root(), &batch, &extra));
+        let mut missing = proof.clone();
+        missing.hashes.pop();
+        assert!(!verify_multiproof(tree.root(), &batch, &missing));
+        let mut unsorted = proof.clone();
+        unsorted.leaf_indices = vec![3, 2, 7];
+        assert!(!verify_multiproof(tree.root(), &batch, &unsorted));
+        assert!(!verify_multiproof(tree.root(), &batch[..2], &proof));
+
+        assert!(matches!(tree.make_multiproof(&[]), Err(MerkleError::EmptyInput)));
+        assert!(matches!(tree.make_multiproof(&[1, 11]), Err(MerkleError::UnknownLeaf{index: 11, ..})));
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<MultiProof>(&json).unwrap(), proof);
+    }
+
+    /**
+     * Leaf by leaf comparison, as merged ranges.
+     */
+    fn naive_diff(a: &MerkleTree, b: &MerkleTree) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        for i in 0..a.leaf_count().max(b.leaf_count()) {
+            if a.leaf(i).is_none() || a.leaf(i) != b.leaf(i) {
+                push_range(&mut ranges, i..i + 1);
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn diff_trees() {
+        let mut rng = thread_rng();
+        for layout in [Layout::Balanced, Layout::Complete].iter().cloned() {
+            for leaves_count in 1..=40 {
+                let data = make_data(leaves_count);
+                let tree = MerkleTree::<Sha3_256>::build(&data, HashScheme::default(), layout).unwrap();
+                assert_eq!(tree.diff(&tree.clone()).unwrap(), Vec::new());
+
+                let mut changed = data.clone();
+                for _ in 0..3 {
+                    let i = rng.gen_range(0, leaves_count);
+                    changed[i] = b"Changed reading".to_vec();
+                }
+                let other = MerkleTree::<Sha3_256>::build(&changed, HashScheme::default(), layout).unwrap();
+                assert_eq!(tree.diff(&other).unwrap(), naive_diff(&tree, &other));
+                assert_eq!(other.diff(&tree).unwrap(), tree.diff(&other).unwrap());
+            }
+        }
+
+        let data = make_data(8);
+        let mut changed = data.clone();
+        changed[2] = b"2".to_vec();
+        changed[3] = b"3".to_vec();
+        changed[6] = b"6".to_vec();
+        let tree = MerkleTree::from_data(&data).unwrap();
+        assert_eq!(tree.diff(&MerkleTree::from_data(&changed).unwrap()).unwrap(), vec![2..4, 6..7]);
+
+        let data = make_data(1 << 12);
+        let mut changed = data.clone();
+        changed[1234] = b"Changed reading".to_vec();
+        let diff = MerkleTree::from_data(&data).unwrap().diff(&MerkleTree::from_data(&changed).unwrap()).unwrap();
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0], 1234..1235);
+    }
+
+    #[test]
+    fn diff_trees_of_different_sizes() {
+        let mut rng = thread_rng();
+        for leaves_count in 1..=40 {
+            let data = make_data(leaves_count);
+            let tree = MerkleTree::from_data(&data).unwrap();
+
+            for shared in 1..=leaves_count {
+                let mut other_data = data[..shared].to_vec();
+                if rng.gen() {
+                    let i = rng.gen_range(0, shared);
+                    other_data[i] = b"Changed reading".to_vec();
+                }
+                let other = MerkleTree::from_data(&other_data).unwrap();
+                assert_eq!(tree.diff(&other).unwrap(), naive_diff(&tree, &other));
+                assert_eq!(other.diff(&tree).unwrap(), naive_diff(&tree, &other));
+            }
+        }
+
+        // Appended leaves only
+        let data = make_data(13);
+        let appended = MerkleTree::from_data(&data[..5]).unwrap().diff(&MerkleTree::from_data(&data).unwrap()).unwrap();
+        assert_eq!(appended.len(), 1);
+        assert_eq!(appended[0], 5..13);
+
+        let complete = MerkleTree::complete_from_data(&data).unwrap();
+        assert!(matches!(complete.diff(&MerkleTree::complete_from_data(&data[..5]).unwrap()), Err(MerkleError::IncompatibleTrees)));
+        assert!(matches!(complete.diff(&MerkleTree::from_data_with_scheme(&data, HashScheme::Legacy).unwrap()), Err(MerkleError::IncompatibleTrees)));
+    }
+
+    #[test]
+    fn proof_serde() {
+        let data: Vec<Vec<u8>> = make_data(7);
+        let data_refs: Vec<&[u8]> = make_data_refs(&data);
+        let tree = MerkleTree::from_data(&data_refs).unwrap();
+        let proof = tree.make_proof(5).unwrap();
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.contains(&to_hex(&proof.path[0].hash)));
+        let decoded: Proof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(verify_proof(tree.root(), data_refs[5], &decoded));
+
+        let truncated = json.replacen(&to_hex(&proof.path[0].hash), "abcd", 1);
+        assert!(serde_json::from_str::<Proof>(&truncated).is_err());
+    }
 
-        assert!(tree.make_proof(&[6u8]).is_err());
+    /**
+     * FNV-1a stretched to 32 bytes: not a cryptographic hash, but fast enough
+     * to encode trees of every size up to thousands of leaves.
+     */
+    struct TestHasher;
+    impl MerkleHasher for TestHasher {
+        fn hash(chunks: &[&[u8]]) -> Hash {
+            let mut state = 0xcbf2_9ce4_8422_2325u64;
+            for chunk in chunks {
+                for byte in chunk.iter() {
+                    state = (state ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+                }
+            }
+
+            let mut hash = [0u8; 32];
+            for chunk in hash.chunks_mut(8) {
+                state = (state ^ 0xff).wrapping_mul(0x100_0000_01b3);
+                chunk.copy_from_slice(&state.to_be_bytes());
+            }
+
+            hash
+        }
+    }
+
+    fn assert_same_tree<H>(decoded: &MerkleTree<H>, tree: &MerkleTree<H>) {
+        assert_eq!(decoded.digests, tree.digests);
+        assert_eq!(decoded.offsets, tree.offsets);
+        assert_eq!(decoded.pairs, tree.pairs);
+        assert_eq!((decoded.leaf_count, decoded.scheme, decoded.layout), (tree.leaf_count, tree.scheme, tree.layout));
+    }
+
+    #[test]
+    fn tree_encoding_roundtrip() {
+        let data: Vec<Vec<u8>> = (0..2100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+
+        for leaves_count in 1..=data.len() {
+            for layout in [Layout::Balanced, Layout::Complete] {
+                let tree = MerkleTree::<TestHasher>::build(&data[..leaves_count], HashScheme::Rfc6962, layout).unwrap();
+                let bytes = tree.to_bytes();
+                assert_eq!(bytes.len(), 12 + 32 * tree.digests.len());
+                assert_same_tree(&MerkleTree::from_bytes(&bytes).unwrap(), &tree);
+
+                if leaves_count <= 100 || leaves_count.is_power_of_two() {
+                    let json = serde_json::to_string(&tree).unwrap();
+                    assert_same_tree(&serde_json::from_str(&json).unwrap(), &tree);
+                }
+            }
+        }
+
+        let tree = MerkleTree::from_data_with_scheme(&data[..10], HashScheme::Legacy).unwrap();
+        assert_same_tree(&MerkleTree::from_bytes(&tree.to_bytes()).unwrap(), &tree);
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_same_tree(&serde_json::from_str(&json).unwrap(), &tree);
+    }
+
+    #[test]
+    fn invalid_tree_encodings() {
+        let data = make_data(13);
+        let tree = MerkleTree::from_data(&data).unwrap();
+        let bytes = tree.to_bytes();
+        let invalid = |bytes: &[u8]| matches!(MerkleTree::<Sha3_256>::from_bytes(bytes), Err(MerkleError::InvalidEncoding));
+
+        // Truncated anywhere, or with trailing bytes
+        for length in 0..bytes.len() {
+            assert!(invalid(&bytes[..length]));
+        }
+        assert!(invalid(&[&bytes[..], &[0]].concat()));
+        assert!(invalid(&[&bytes[..], &[0u8; 32]].concat()));
+
+        // Another version, tag, scheme or layout
+        for (offset, byte) in [(0, ENCODING_VERSION + 1), (1, PROOF_TAG), (2, 2), (3, 2), (3, 1)] {
+            let mut changed = bytes.clone();
+            changed[offset] = byte;
+            assert!(invalid(&changed));
+        }
+
+        // A leaf count that does not match the digests
+        for leaf_count in [0u64, 12, 14, u64::MAX] {
+            let mut changed = bytes.clone();
+            changed[4..12].copy_from_slice(&leaf_count.to_be_bytes());
+            assert!(invalid(&changed));
+        }
+
+        // Any digest that is not the hash of its children, the root included
+        for digest in 0..tree.digests.len() {
+            let mut changed = bytes.clone();
+            changed[12 + 32 * digest] ^= 1;
+            assert!(invalid(&changed));
+        }
+
+        // Decoding with another hash function
+        assert!(invalid(&MerkleTree::<Keccak256>::build(&data, HashScheme::Rfc6962, Layout::Balanced).unwrap().to_bytes()));
+
+        let mut json: serde_json::Value = serde_json::to_value(&tree).unwrap();
+        json["digests"][3] = serde_json::Value::String(to_hex(&[0u8; 32]));
+        assert!(serde_json::from_value::<MerkleTree>(json).is_err());
+    }
+
+    #[test]
+    fn proof_encoding() {
+        let data = make_data(100);
+
+        for leaves_count in 1..=data.len() {
+            for layout in [Layout::Balanced, Layout::Complete] {
+                let tree = MerkleTree::<TestHasher>::build(&data[..leaves_count], HashScheme::Rfc6962, layout).unwrap();
+
+                for index in 0..leaves_count {
+                    let proof = tree.make_proof(index).unwrap();
+                    let bytes = proof.to_bytes();
+                    assert_eq!(bytes.len(), 19 + 32 * proof.path.len());
+                    assert_eq!(Proof::from_bytes(&bytes).unwrap(), proof);
+
+                    assert!(Proof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+                    assert!(Proof::from_bytes(&[&bytes[..], &[0u8; 32]].concat()).is_err());
+                }
+            }
+        }
+
+        let tree = MerkleTree::from_data(&data).unwrap();
+        let bytes = tree.make_proof(42).unwrap().to_bytes();
+        for length in 0..bytes.len() {
+            assert!(matches!(Proof::from_bytes(&bytes[..length]), Err(MerkleError::InvalidEncoding)));
+        }
+
+        // An index out of range, or a count that calls for another path length
+        for (offset, value) in [(3, 100u64), (3, u64::MAX), (11, 42), (11, 1000), (11, u64::MAX)] {
+            let mut changed = bytes.clone();
+            changed[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+            assert!(Proof::from_bytes(&changed).is_err());
+        }
+
+        let root = *tree.root();
+        assert_eq!(encode_root(&root).len(), 34);
+        assert_eq!(decode_root(&encode_root(&root)).unwrap(), root);
+        assert!(decode_root(&encode_root(&root)[..33]).is_err());
+        assert!(decode_root(&bytes).is_err());
+        assert!(decode_root(&[&encode_root(&root)[..], &[0]].concat()).is_err());
     }
 
     #[test]
     fn single_node_tree() {
         let data = make_data(1);
         let refs = make_data_refs(&data);
-        let tree = MerkleTree::from_data(&refs);
+        let tree = MerkleTree::from_data(&refs).unwrap();
 
-        assert_eq!(tree.count_leaves(), 1);
-        assert_eq!(tree.count_nodes(), 1);
-        assert_eq!(tree.node_depth(&tree.root), 0);
+        assert_eq!(tree.leaf_count(), 1);
+        assert_eq!(tree.count_branches(), 0);
+        assert_eq!(tree.height(), 0);
+        assert_eq!(*tree.root(), tree.scheme().hash_leaf::<Sha3_256>(refs[0]));
 
-        assert!(tree.make_proof(refs[0]).unwrap().is_empty());
-        assert!(tree.authenticate(refs[0], &tree.make_proof(refs[0]).unwrap()));
+        assert!(tree.make_proof(0).unwrap().path.is_empty());
+        assert!(tree.authenticate(refs[0], &tree.make_proof(0).unwrap()));
     }
 
     #[test]
@@ -414,14 +2012,111 @@ mod tests {
             let last_filled_level = (ln as f64).log2().floor() as u32;
             let leaves_on_last_level = (ln - 2usize.pow(last_filled_level)) * 2; // multiple of 2
 
-            println!("Leaves={}, last_filled_level={}, leaves_last_level={}", ln, last_filled_level, leaves_on_last_level);
             assert!(leaves_on_last_level < 2usize.pow(last_filled_level+1));
-
             assert_eq!(leaves_on_last_level % 2, 0);
-            // put first <leaves_on_last_level> leaves on the stack in rev order   | O(L-1)
-            // while (take 2 nodes from the stack):
-            //   make parent branch;                                               | O(k)
-            //   push branch on TOP of the stack;                                  | O(2)
+            assert_eq!(log2_floor(ln), last_filled_level as usize);
+
+            for index in 0..ln {
+                let depth = complete_leaf_depth(index, ln).unwrap();
+                match index < leaves_on_last_level {
+                    true => assert_eq!(depth, last_filled_level as usize + 1),
+                    false => assert_eq!(depth, last_filled_level as usize),
+                }
+            }
+            assert_eq!(complete_leaf_depth(ln, ln), None);
+        }
+    }
+
+    #[test]
+    fn complete_tree_proofs() {
+        for leaves_count in 1..=70 {
+            let data: Vec<Vec<u8>> = make_data(leaves_count);
+            let tree = MerkleTree::complete_from_data(&data).unwrap();
+
+            assert_eq!(tree.layout(), Layout::Complete);
+            assert_eq!(tree.count_branches(), leaves_count - 1);
+            assert_eq!(tree.height(), (leaves_count as f64).log2().ceil() as usize);
+
+            for (i, datum) in data.iter().enumerate() {
+                let proof = tree.make_proof(i).unwrap();
+                assert_eq!(proof.layout, Layout::Complete);
+                assert_eq!(proof.path.len(), complete_leaf_depth(i, leaves_count).unwrap());
+                assert!(tree.authenticate(datum, &proof));
+                assert!(verify_proof(tree.root(), datum, &proof));
+
+                // The layout is part of the index binding
+                let mut balanced = proof.clone();
+                balanced.layout = Layout::Balanced;
+                if path_sides(Layout::Balanced, i, leaves_count) != path_sides(Layout::Complete, i, leaves_count) {
+                    assert!(!verify_proof(tree.root(), datum, &balanced));
+                }
+            }
+        }
+
+        // Perfect trees have the same shape with both layouts
+        let data = make_data(16);
+        assert_eq!(MerkleTree::complete_from_data(&data).unwrap().root(), MerkleTree::from_data(&data).unwrap().root());
+
+        // 5 leaves: ((1, 2), (3, 4)), 5 when balanced, ((1, 2), 3), (4, 5) when complete
+        let data = make_data(5);
+        let leaves: Vec<Hash> = data.iter().map(|d| HashScheme::default().hash_leaf::<Sha3_256>(d)).collect();
+        let branch = |l: &Hash, r: &Hash| HashScheme::default().hash_branch::<Sha3_256>(l, r);
+        assert_eq!(*MerkleTree::from_data(&data).unwrap().root(),
+            branch(&branch(&branch(&leaves[0], &leaves[1]), &branch(&leaves[2], &leaves[3])), &leaves[4]));
+        assert_eq!(*MerkleTree::complete_from_data(&data).unwrap().root(),
+            branch(&branch(&branch(&leaves[0], &leaves[1]), &leaves[2]), &branch(&leaves[3], &leaves[4])));
+    }
+
+    #[test]
+    fn node_store() {
+        let data = make_data(50);
+
+        for layout in [Layout::Balanced, Layout::Complete] {
+            for leaves_count in [1, 2, 5, 32, 50] {
+                let tree = MerkleTree::<Sha3_256>::build(&data[..leaves_count], HashScheme::Rfc6962, layout).unwrap();
+                let mut store = MemoryStore::new();
+                tree.save(&mut store).unwrap();
+                assert_eq!(store.roots(), [*tree.root()]);
+                assert_eq!(store.node_count(), 2 * leaves_count - 1);
+
+                let loaded = MerkleTree::<Sha3_256>::load(&store, tree.root(), HashScheme::Rfc6962, layout).unwrap();
+                assert_eq!(loaded.to_bytes(), tree.to_bytes());
+            }
         }
+
+        // A new version only adds the nodes on the updated leaf's path
+        let dir = TestDir::new("merkletree-store");
+        let mut tree = MerkleTree::from_data(&data).unwrap();
+        let first = *tree.root();
+        tree.save(&mut FileStore::open(&dir.0).unwrap()).unwrap();
+        tree.update_leaf(7, b"corrected").unwrap();
+        let mut store = FileStore::open(&dir.0).unwrap();
+        tree.save(&mut store).unwrap();
+        assert_eq!(store.node_count(), 2 * data.len() - 1 + 1 + tree.make_proof(7).unwrap().path.len());
+
+        let store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.roots(), [first, *tree.root()]);
+        let old = MerkleTree::<Sha3_256>::load(&store, &first, HashScheme::Rfc6962, Layout::Balanced).unwrap();
+        assert_eq!(old.root(), MerkleTree::from_data(&data).unwrap().root());
+        let new = MerkleTree::<Sha3_256>::load(&store, tree.root(), HashScheme::Rfc6962, Layout::Balanced).unwrap();
+        assert_eq!(new.to_bytes(), tree.to_bytes());
+
+        // Missing and corrupt nodes, another layout
+        assert!(matches!(MerkleTree::<Sha3_256>::load(&store, &[7u8; 32], HashScheme::Rfc6962, Layout::Balanced), Err(MerkleError::MissingNode(_))));
+        assert!(matches!(MerkleTree::<Sha3_256>::load(&store, &first, HashScheme::Legacy, Layout::Balanced), Err(MerkleError::CorruptNode(_))));
+        assert!(matches!(MerkleTree::<Sha3_256>::load(&store, &first, HashScheme::Rfc6962, Layout::Complete), Err(MerkleError::IncompatibleTrees)));
+
+        let mut store = MemoryStore::new();
+        store.put(tree.root(), &[BRANCH_PREFIX; 65]).unwrap();
+        tree.save(&mut store).unwrap();
+        assert!(matches!(MerkleTree::<Sha3_256>::load(&store, tree.root(), HashScheme::Rfc6962, Layout::Balanced), Err(MerkleError::CorruptNode(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn proof_without_layout() {
+        // Proofs serialized before the layout was introduced default to Balanced
+        let json = r#"{"leaf_index":0,"leaf_count":1,"path":[]}"#;
+        let proof: Proof = serde_json::from_str(json).unwrap();
+        assert_eq!(proof.layout, Layout::Balanced);
+    }
+}
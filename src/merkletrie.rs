@@ -0,0 +1,612 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use crate::merkletree::{Hash, MerkleError, MerkleHasher, Sha3_256};
+use crate::nodestore::{NodeStore, MemoryStore, PruneReport, Retention};
+
+/**
+ * Node of the trie: a compressed run of nibbles, then either the end of an
+ * address (`value`) or a branch on the next nibble (`children`).
+ * Apart from the root, a node always has a value or at least two children.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Node {
+    prefix: Vec<u8>, // nibbles
+    value: Option<Vec<u8>>,
+    children: [Option<Hash>; 16],
+}
+
+impl Node {
+    /**
+     * Deterministic encoding, the node's hash is the hash of it:
+     * prefix length (u32 BE) and nibbles, value flag and length (u32 BE) and bytes,
+     * children bitmap (u16 BE) and the hashes of the present children.
+     */
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.prefix.len() + 32 * 16);
+        bytes.extend_from_slice(&(self.prefix.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.prefix);
+
+        match &self.value {
+            Some(value) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(value);
+            }
+            None => bytes.push(0),
+        }
+
+        let mut bitmap = 0u16;
+        for (nibble, child) in self.children.iter().enumerate() {
+            if child.is_some() {
+                bitmap |= 1 << nibble;
+            }
+        }
+        bytes.extend_from_slice(&bitmap.to_be_bytes());
+        for child in self.children.iter().flatten() {
+            bytes.extend_from_slice(child);
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Node> {
+        let mut node = Node::default();
+        let mut bytes = bytes;
+
+        let length = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap()) as usize;
+        node.prefix = take(&mut bytes, length)?.to_vec();
+        if node.prefix.iter().any(|nibble| *nibble > 0x0f) {
+            return None;
+        }
+
+        match take(&mut bytes, 1)? {
+            [0] => {}
+            [1] => {
+                let length = u32::from_be_bytes(take(&mut bytes, 4)?.try_into().unwrap()) as usize;
+                node.value = Some(take(&mut bytes, length)?.to_vec());
+            }
+            _ => return None,
+        }
+
+        let bitmap = u16::from_be_bytes(take(&mut bytes, 2)?.try_into().unwrap());
+        for nibble in 0..16 {
+            if bitmap & (1 << nibble) != 0 {
+                node.children[nibble] = Some(take(&mut bytes, 32)?.try_into().unwrap());
+            }
+        }
+
+        match bytes.is_empty() {
+            true => Some(node),
+            false => None,
+        }
+    }
+
+    fn child_count(&self) -> usize {
+        self.children.iter().filter(|child| child.is_some()).count()
+    }
+}
+
+/**
+ * Radix (Patricia) Merkle trie over byte addresses, as Sawtooth's global state.
+ *
+ * Addresses are split into nibbles, and each node is stored under the hash of
+ * its encoding, so a root commits to the whole state. Nodes are never modified:
+ * a commit writes new nodes along the changed paths only and yields a new root,
+ * while the unchanged subtrees are shared with the previous versions.
+ * Any root committed so far can still be queried with `get(root, address)`.
+ *
+ * Nodes and roots are kept by a NodeStore, in memory by default. With a
+ * FileStore the state, and all of its versions, survives restarts.
+ */
+pub struct MerkleTrie<H = Sha3_256, S = MemoryStore> {
+    store: S, // committed roots are the store's, the empty trie's first
+    hasher: PhantomData<fn() -> H>,
+}
+impl<H: MerkleHasher> Default for MerkleTrie<H> {
+    fn default() -> Self {
+        MerkleTrie::with_store(MemoryStore::new()).unwrap()
+    }
+}
+
+impl MerkleTrie {
+    pub fn new() -> MerkleTrie {
+        MerkleTrie::default()
+    }
+}
+
+impl<H: MerkleHasher, S: NodeStore> MerkleTrie<H, S> {
+    /**
+     * Trie over the nodes of `store`, whose head is the last root committed to it.
+     * An empty store starts with the empty trie.
+     */
+    pub fn with_store(store: S) -> Result<Self, MerkleError> {
+        let mut trie = MerkleTrie{
+            store,
+            hasher: PhantomData,
+        };
+        if trie.store.roots().is_empty() {
+            let empty = trie.put_node(Node::default())?;
+            trie.store.push_root(&empty)?;
+        }
+
+        Ok(trie)
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /**
+     * Root of the last commit.
+     */
+    pub fn head(&self) -> &Hash {
+        self.store.roots().last().unwrap()
+    }
+
+    /**
+     * Every root committed so far, oldest first.
+     */
+    pub fn roots(&self) -> &[Hash] {
+        self.store.roots()
+    }
+
+    /**
+     * Value at `address` in the state as of `root`.
+     */
+    pub fn get(&self, root: &Hash, address: &[u8]) -> Result<Option<Vec<u8>>, MerkleError> {
+        let path = nibbles(address);
+        let mut path = &path[..];
+        let mut node = self.node(root)?;
+
+        loop {
+            if !path.starts_with(&node.prefix) {
+                return Ok(None);
+            }
+            path = &path[node.prefix.len()..];
+
+            if path.is_empty() {
+                return Ok(node.value.clone());
+            }
+            node = match &node.children[path[0] as usize] {
+                Some(child) => self.node(child)?,
+                None => return Ok(None),
+            };
+            path = &path[1..];
+        }
+    }
+
+    /**
+     * Applies the writes, then the deletions, on top of the head and returns the new root.
+     */
+    pub fn commit<A: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, set: &[(A, V)], delete: &[A]) -> Result<Hash, MerkleError> {
+        // Intermediate roots are not stored, only the nodes below them
+        let mut root = Some(self.node(self.head())?);
+
+        for (address, value) in set {
+            root = Some(self.insert(root, &nibbles(address.as_ref()), value.as_ref())?);
+        }
+        for address in delete {
+            if let Some(node) = root {
+                root = self.remove(node, &nibbles(address.as_ref()))?;
+            }
+        }
+
+        // Inserting into the empty trie leaves a root without value and a single child
+        let root = match root {
+            Some(node) => self.compress(node)?,
+            None => None,
+        };
+        let root = self.put_node(root.unwrap_or_default())?;
+        self.store.push_root(&root)?;
+
+        Ok(root)
+    }
+
+    /**
+     * Keeps the roots chosen by `retention` and frees the nodes none of them reaches.
+     * The pruned roots can no longer be queried.
+     */
+    pub fn prune(&mut self, retention: &Retention) -> Result<PruneReport, MerkleError> {
+        let roots = retention.kept_roots(self.roots());
+        let mut live = HashSet::new();
+        let mut pending = roots.clone();
+
+        // Shared subtrees are only visited once
+        while let Some(hash) = pending.pop() {
+            if live.insert(hash) {
+                pending.extend(self.node(&hash)?.children.iter().flatten());
+            }
+        }
+
+        Ok(self.store.retain(&live, &roots)?)
+    }
+
+    fn node(&self, hash: &Hash) -> Result<Node, MerkleError> {
+        let bytes = self.store.get(hash)?.ok_or(MerkleError::MissingNode(*hash))?;
+        Node::decode(&bytes).ok_or(MerkleError::CorruptNode(*hash))
+    }
+
+    fn put_node(&mut self, node: Node) -> Result<Hash, MerkleError> {
+        let bytes = node.encode();
+        let hash = H::hash(&[&bytes]);
+        self.store.put(&hash, &bytes)?;
+
+        Ok(hash)
+    }
+
+    /**
+     * Copy of `node` with `value` at `path`, new nodes below it are stored.
+     */
+    fn insert(&mut self, node: Option<Node>, path: &[u8], value: &[u8]) -> Result<Node, MerkleError> {
+        let node = match node {
+            Some(node) => node,
+            None => return Ok(Node{prefix: path.to_vec(), value: Some(value.to_vec()), ..Node::default()}),
+        };
+
+        let common = node.prefix.iter().zip(path).take_while(|(a, b)| a == b).count();
+
+        if common < node.prefix.len() {
+            // Split the prefix: the old node moves below a new one
+            let mut old = node;
+            let nibble = old.prefix[common] as usize;
+            old.prefix = old.prefix[common + 1..].to_vec();
+
+            let mut parent = Node{prefix: path[..common].to_vec(), ..Node::default()};
+            parent.children[nibble] = Some(self.put_node(old)?);
+
+            if common == path.len() {
+                parent.value = Some(value.to_vec());
+            } else {
+                let leaf = Node{prefix: path[common + 1..].to_vec(), value: Some(value.to_vec()), ..Node::default()};
+                parent.children[path[common] as usize] = Some(self.put_node(leaf)?);
+            }
+
+            return Ok(parent);
+        }
+
+        let mut updated = node;
+        let rest = &path[common..];
+        if rest.is_empty() {
+            updated.value = Some(value.to_vec());
+        } else {
+            let nibble = rest[0] as usize;
+            let child = match &updated.children[nibble] {
+                Some(child) => Some(self.node(child)?),
+                None => None,
+            };
+            let child = self.insert(child, &rest[1..], value)?;
+            updated.children[nibble] = Some(self.put_node(child)?);
+        }
+
+        Ok(updated)
+    }
+
+    /**
+     * Copy of `node` without a value at `path`, None if nothing is left of it.
+     */
+    fn remove(&mut self, node: Node, path: &[u8]) -> Result<Option<Node>, MerkleError> {
+        if !path.starts_with(&node.prefix) {
+            return Ok(Some(node));
+        }
+
+        let mut updated = node;
+        let rest = &path[updated.prefix.len()..];
+        if rest.is_empty() {
+            updated.value = None;
+        } else {
+            let nibble = rest[0] as usize;
+            let child = match &updated.children[nibble] {
+                Some(child) => self.node(child)?,
+                None => return Ok(Some(updated)),
+            };
+            updated.children[nibble] = match self.remove(child, &rest[1..])? {
+                Some(child) => Some(self.put_node(child)?),
+                None => None,
+            };
+        }
+
+        self.compress(updated)
+    }
+
+    /**
+     * Keeps the trie compressed: a node without value is dropped when it has
+     * no children and merged with its child when it has only one.
+     * The same addresses and values then always give the same root.
+     */
+    fn compress(&self, node: Node) -> Result<Option<Node>, MerkleError> {
+        if node.value.is_some() {
+            return Ok(Some(node));
+        }
+
+        match node.child_count() {
+            0 => Ok(None),
+            1 => {
+                let (nibble, child) = node.children.iter().enumerate()
+                    .find_map(|(nibble, child)| child.map(|child| (nibble, child)))
+                    .unwrap();
+                let mut merged = self.node(&child)?;
+                let mut prefix = node.prefix;
+                prefix.push(nibble as u8);
+                prefix.extend_from_slice(&merged.prefix);
+                merged.prefix = prefix;
+
+                Ok(Some(merged))
+            }
+            _ => Ok(Some(node)),
+        }
+    }
+}
+
+/**
+ * Splits off the first `count` bytes.
+ */
+fn take<'a>(bytes: &mut &'a [u8], count: usize) -> Option<&'a [u8]> {
+    if bytes.len() < count {
+        return None;
+    }
+
+    let (taken, rest) = bytes.split_at(count);
+    *bytes = rest;
+
+    Some(taken)
+}
+
+fn nibbles(address: &[u8]) -> Vec<u8> {
+    address.iter().flat_map(|byte| vec![byte >> 4, byte & 0x0f]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::nodestore::FileStore;
+    use crate::nodestore::tests::TestDir;
+
+    fn address(id: usize) -> Vec<u8> {
+        Sha3_256::hash(&[format!("Participant {}", id).as_bytes()]).to_vec()
+    }
+
+    #[test]
+    fn empty_trie() {
+        let trie = MerkleTrie::new();
+
+        assert_eq!(trie.roots(), &[*trie.head()]);
+        assert_eq!(trie.get(trie.head(), b"address").unwrap(), None);
+        assert!(matches!(trie.get(&[7u8; 32], b"address"), Err(MerkleError::MissingNode(hash)) if hash == [7u8; 32]));
+    }
+
+    #[test]
+    fn get_set_delete() {
+        let mut trie = MerkleTrie::new();
+        let empty = *trie.head();
+
+        let set: Vec<(Vec<u8>, Vec<u8>)> = (0..100).map(|id| (address(id), format!("{} kWh", id).into_bytes())).collect();
+        let root = trie.commit(&set, &[]).unwrap();
+        assert_eq!(*trie.head(), root);
+
+        for (address, value) in &set {
+            assert_eq!(trie.get(&root, address).unwrap(), Some(value.clone()));
+        }
+        assert_eq!(trie.get(&root, &address(100)).unwrap(), None);
+
+        // Same content, same root, whatever the order of the writes
+        let mut other = MerkleTrie::new();
+        for (address, value) in set.iter().rev() {
+            other.commit(&[(address, value)], &[]).unwrap();
+        }
+        assert_eq!(*other.head(), root);
+
+        let addresses: Vec<Vec<u8>> = set.iter().map(|(address, _)| address.clone()).collect();
+        let cleared = trie.commit::<_, Vec<u8>>(&[], &addresses).unwrap();
+        assert_eq!(cleared, empty);
+    }
+
+    #[test]
+    fn prefixes_of_other_addresses() {
+        // Addresses may end inside the path of longer ones
+        let mut trie = MerkleTrie::new();
+        let root = trie.commit(&[(&b"ab"[..], &b"1"[..]), (b"abcd", b"2"), (b"abce", b"3"), (b"", b"4")], &[]).unwrap();
+
+        assert_eq!(trie.get(&root, b"ab").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(trie.get(&root, b"abcd").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(trie.get(&root, b"abce").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(trie.get(&root, b"").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(trie.get(&root, b"abc").unwrap(), None);
+        assert_eq!(trie.get(&root, b"a").unwrap(), None);
+
+        let root = trie.commit::<_, &[u8]>(&[], &[&b"ab"[..], b"abce", b"missing"]).unwrap();
+        assert_eq!(trie.get(&root, b"ab").unwrap(), None);
+        assert_eq!(trie.get(&root, b"abcd").unwrap(), Some(b"2".to_vec()));
+
+        let mut other = MerkleTrie::new();
+        other.commit(&[(&b"abcd"[..], &b"2"[..]), (b"", b"4")], &[]).unwrap();
+        assert_eq!(other.head(), &root);
+
+        // A single address, whether written alone or left after deletions
+        let root = trie.commit::<_, &[u8]>(&[], &[&b""[..]]).unwrap();
+        let mut single = MerkleTrie::new();
+        single.commit(&[(&b"abcd"[..], &b"2"[..])], &[]).unwrap();
+        assert_eq!(single.head(), &root);
+    }
+
+    #[test]
+    fn versioned_roots() {
+        let mut trie = MerkleTrie::new();
+        let mut history = Vec::new();
+        let mut state = BTreeMap::new();
+
+        // A block of trades per commit: some balances change, some accounts close
+        for block in 0..20usize {
+            let set: Vec<(Vec<u8>, Vec<u8>)> = (0..10)
+                .map(|i| (address((block * 7 + i) % 40), format!("balance {}", block).into_bytes()))
+                .collect();
+            let delete = vec![address((block * 3) % 40)];
+
+            let root = trie.commit(&set, &delete).unwrap();
+            for (address, value) in set {
+                state.insert(address, value);
+            }
+            state.remove(&delete[0]);
+            history.push((root, state.clone()));
+            assert_eq!(trie.get(&root, &delete[0]).unwrap(), None);
+        }
+        assert_eq!(trie.roots().len(), 21);
+
+        // Every past root still answers with the state as of its commit
+        for (root, state) in &history {
+            for id in 0..40 {
+                assert_eq!(trie.get(root, &address(id)).unwrap(), state.get(&address(id)).cloned());
+            }
+        }
+    }
+
+    #[test]
+    fn shared_subtrees() {
+        let mut trie = MerkleTrie::new();
+        let set: Vec<(Vec<u8>, Vec<u8>)> = (0..256).map(|id| (address(id), b"credits".to_vec())).collect();
+        let before = trie.commit(&set, &[]).unwrap();
+        let nodes = trie.store().node_count();
+
+        // One write only stores the nodes along its path
+        trie.commit(&[(address(3), b"more credits")], &[]).unwrap();
+        let depth = 4; // about log16(256) + 1
+        assert!(trie.store().node_count() - nodes <= depth + 1);
+
+        // The subtrees of the other nibbles are the same nodes in both versions
+        let old_root = trie.node(&before).unwrap();
+        let new_root = trie.node(trie.head()).unwrap();
+        let nibble = nibbles(&address(3))[0] as usize;
+        for i in 0..16 {
+            match i == nibble {
+                true => assert_ne!(old_root.children[i], new_root.children[i]),
+                false => assert_eq!(old_root.children[i], new_root.children[i]),
+            }
+        }
+    }
+
+    #[test]
+    fn node_encoding() {
+        let mut node = Node{prefix: vec![0, 15, 3], value: Some(b"value".to_vec()), ..Node::default()};
+        node.children[0] = Some([1u8; 32]);
+        node.children[15] = Some([2u8; 32]);
+
+        for node in [node.clone(), Node::default()].iter() {
+            let bytes = node.encode();
+            assert_eq!(Node::decode(&bytes).as_ref(), Some(node));
+            assert_eq!(Node::decode(&bytes[..bytes.len() - 1]), None);
+            assert_eq!(Node::decode(&[&bytes[..], &[0]].concat()), None);
+        }
+
+        let mut invalid = node.clone();
+        invalid.prefix.push(16);
+        assert_eq!(Node::decode(&invalid.encode()), None);
+    }
+
+    #[test]
+    fn file_backed_trie() {
+        let dir = TestDir::new("merkle-trie");
+        let mut history = Vec::new();
+        {
+            let mut trie = MerkleTrie::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap()).unwrap();
+            for block in 0..5 {
+                let set: Vec<(Vec<u8>, Vec<u8>)> = (0..20).map(|id| (address(id), format!("{} {}", block, id).into_bytes())).collect();
+                history.push(trie.commit(&set, &[address(block)]).unwrap());
+            }
+        }
+
+        // The head and every past root survive a restart
+        let mut trie = MerkleTrie::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap()).unwrap();
+        assert_eq!(trie.roots()[1..], history[..]);
+        assert_eq!(trie.head(), history.last().unwrap());
+        for (block, root) in history.iter().enumerate() {
+            assert_eq!(trie.get(root, &address(block)).unwrap(), None);
+            assert_eq!(trie.get(root, &address(10)).unwrap(), Some(format!("{} 10", block).into_bytes()));
+        }
+
+        // Same roots as in memory
+        let root = trie.commit(&[(address(99), b"new")], &[]).unwrap();
+        let mut memory = MerkleTrie::new();
+        for block in 0..5 {
+            let set: Vec<(Vec<u8>, Vec<u8>)> = (0..20).map(|id| (address(id), format!("{} {}", block, id).into_bytes())).collect();
+            memory.commit(&set, &[address(block)]).unwrap();
+        }
+        assert_eq!(memory.roots()[1..6], history[..]);
+        assert_eq!(memory.commit(&[(address(99), b"new")], &[]).unwrap(), root);
+    }
+
+    fn trade_block(block: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..10).map(|i| (address((block * 7 + i) % 40), format!("balance {}", block).into_bytes())).collect()
+    }
+
+    #[test]
+    fn prune() {
+        let mut trie = MerkleTrie::new();
+        for block in 0..20 {
+            trie.commit(&trade_block(block), &[]).unwrap();
+        }
+        let roots = trie.roots().to_vec();
+        let nodes = trie.store().node_count();
+
+        let report = trie.prune(&Retention::LastRoots(3)).unwrap();
+        assert_eq!(trie.roots(), &roots[18..]);
+        assert!(report.nodes_freed > 0 && report.bytes_freed > 0);
+        assert_eq!(trie.store().node_count(), nodes - report.nodes_freed);
+        assert_eq!(trie.prune(&Retention::LastRoots(3)).unwrap(), PruneReport::default());
+
+        // The kept versions are intact, the others are gone
+        let mut fresh = MerkleTrie::new();
+        for (block, root) in roots.iter().enumerate().skip(1) {
+            fresh.commit(&trade_block(block - 1), &[]).unwrap();
+            if block < 18 {
+                assert!(matches!(trie.get(root, &address(0)), Err(MerkleError::MissingNode(_))));
+                continue;
+            }
+            for id in 0..40 {
+                assert_eq!(trie.get(root, &address(id)).unwrap(), fresh.get(root, &address(id)).unwrap());
+            }
+        }
+
+        // Pinned roots survive whatever their age, the head too
+        let audited = roots[18];
+        for block in 20..30 {
+            trie.commit(&trade_block(block), &[]).unwrap();
+        }
+        let head = *trie.head();
+        let pinned = [audited].iter().cloned().collect();
+        trie.prune(&Retention::Pinned(pinned)).unwrap();
+        assert_eq!(trie.roots(), &[audited, head]);
+        assert_eq!(trie.get(&audited, &address(0)).unwrap(), fresh.get(&audited, &address(0)).unwrap());
+
+        // The store stops growing when pruning as it goes
+        let mut sizes = Vec::new();
+        for block in 30..60 {
+            trie.commit(&trade_block(block), &[]).unwrap();
+            trie.prune(&Retention::LastRoots(2)).unwrap();
+            sizes.push(trie.store().node_count());
+        }
+        assert!(sizes[10..].iter().all(|size| *size <= sizes[..10].iter().max().unwrap() + 5));
+    }
+
+    #[test]
+    fn prune_file_backed_trie() {
+        let dir = TestDir::new("merkle-trie-prune");
+        let mut trie = MerkleTrie::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap()).unwrap();
+        for block in 0..10 {
+            trie.commit(&trade_block(block), &[]).unwrap();
+        }
+        let log_size = || std::fs::metadata(dir.0.join("nodes")).unwrap().len();
+        let before = log_size();
+
+        let report = trie.prune(&Retention::LastRoots(1)).unwrap();
+        assert_eq!(log_size(), before - report.bytes_freed);
+
+        let head = *trie.head();
+        drop(trie);
+        let trie = MerkleTrie::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap()).unwrap();
+        assert_eq!(trie.roots(), &[head]);
+        let (address, value) = &trade_block(9)[0];
+        assert_eq!(trie.get(&head, address).unwrap(), Some(value.clone()));
+    }
+}
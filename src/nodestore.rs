@@ -0,0 +1,469 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::merkletree::Hash;
+
+/**
+ * Content-addressed storage of encoded Merkle nodes, plus the list of
+ * committed roots, oldest first. Nodes are never overwritten: putting a
+ * hash that is already stored does nothing.
+ */
+pub trait NodeStore {
+    fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>>;
+    fn put(&mut self, hash: &Hash, node: &[u8]) -> io::Result<()>;
+    fn node_count(&self) -> usize;
+
+    fn roots(&self) -> &[Hash];
+    fn push_root(&mut self, root: &Hash) -> io::Result<()>;
+
+    /**
+     * Drops every node not in `live` and replaces the committed roots with `roots`.
+     * Used by the trees' prune, which find the nodes reachable from the kept roots.
+     */
+    fn retain(&mut self, live: &HashSet<Hash>, roots: &[Hash]) -> io::Result<PruneReport>;
+}
+
+/**
+ * Roots, and the nodes reachable from them, that survive a prune.
+ * The last committed root is always kept.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Retention {
+    LastRoots(usize),
+    Pinned(HashSet<Hash>),
+}
+
+impl Retention {
+    /**
+     * The kept roots among `roots`, in the same order.
+     */
+    pub fn kept_roots(&self, roots: &[Hash]) -> Vec<Hash> {
+        let last = roots.len().saturating_sub(1);
+
+        roots.iter().enumerate()
+            .filter(|(i, root)| *i == last || match self {
+                Retention::LastRoots(count) => i + count >= roots.len(),
+                Retention::Pinned(pinned) => pinned.contains(*root),
+            })
+            .map(|(_, root)| *root)
+            .collect()
+    }
+}
+
+/**
+ * What a prune reclaimed. Bytes are counted as the store keeps them,
+ * e.g. record headers included for the FileStore.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PruneReport {
+    pub nodes_freed: usize,
+    pub bytes_freed: u64,
+}
+
+/**
+ * Nodes and roots in memory only, lost on restart.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    nodes: HashMap<Hash, Vec<u8>>,
+    roots: Vec<Hash>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl NodeStore for MemoryStore {
+    fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.nodes.get(hash).cloned())
+    }
+
+    fn put(&mut self, hash: &Hash, node: &[u8]) -> io::Result<()> {
+        self.nodes.entry(*hash).or_insert_with(|| node.to_vec());
+        Ok(())
+    }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn roots(&self) -> &[Hash] {
+        &self.roots
+    }
+
+    fn push_root(&mut self, root: &Hash) -> io::Result<()> {
+        self.roots.push(*root);
+        Ok(())
+    }
+
+    fn retain(&mut self, live: &HashSet<Hash>, roots: &[Hash]) -> io::Result<PruneReport> {
+        let mut report = PruneReport::default();
+        self.nodes.retain(|hash, node| {
+            let keep = live.contains(hash);
+            if !keep {
+                report.nodes_freed += 1;
+                report.bytes_freed += node.len() as u64;
+            }
+            keep
+        });
+        self.roots = roots.to_vec();
+
+        Ok(report)
+    }
+}
+
+const NODES_FILE: &str = "nodes";
+const ROOTS_FILE: &str = "roots";
+const COMPACT_SUFFIX: &str = ".compact";
+const RECORD_HEADER: usize = 32 + 4; // hash, node length (u32 BE)
+
+type Index = HashMap<Hash, (u64, u32)>; // offset and length of the encoded node
+
+/**
+ * Nodes appended to a log file, `<dir>/nodes`, as records of hash, length and
+ * encoded node. Only the index from hash to position in the log is kept in
+ * memory, it is rebuilt by scanning the log on open. Committed roots are
+ * appended to `<dir>/roots`.
+ *
+ * The node log is synced before a root is appended, so a root that survived a
+ * crash always has all of its nodes. A record cut short by a crash is dropped
+ * on open. Pruning compacts both files: the live records are copied to new
+ * ones, which then replace the old ones.
+ */
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    nodes: RefCell<File>, // reads seek, appends always go to the end
+    roots_file: File,
+    index: Index,
+    roots: Vec<Hash>,
+    end: u64,
+}
+
+impl FileStore {
+    /**
+     * Opens the store in `dir`, creating it if needed.
+     */
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<FileStore> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut nodes = OpenOptions::new().read(true).append(true).create(true).open(dir.join(NODES_FILE))?;
+        let (index, end) = scan_nodes(&mut nodes)?;
+        if end < nodes.metadata()?.len() {
+            nodes.set_len(end)?;
+        }
+
+        let mut roots_file = OpenOptions::new().read(true).append(true).create(true).open(dir.join(ROOTS_FILE))?;
+        let mut bytes = Vec::new();
+        roots_file.read_to_end(&mut bytes)?;
+        let roots: Vec<Hash> = bytes.chunks_exact(32).map(|root| root.try_into().unwrap()).collect();
+        if bytes.len() % 32 != 0 {
+            roots_file.set_len(32 * roots.len() as u64)?;
+        }
+
+        Ok(FileStore{
+            dir: dir.to_path_buf(),
+            nodes: RefCell::new(nodes),
+            roots_file,
+            index,
+            roots,
+            end,
+        })
+    }
+}
+
+impl NodeStore for FileStore {
+    fn get(&self, hash: &Hash) -> io::Result<Option<Vec<u8>>> {
+        let (offset, length) = match self.index.get(hash) {
+            Some(position) => *position,
+            None => return Ok(None),
+        };
+
+        let mut file = self.nodes.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut node = vec![0u8; length as usize];
+        file.read_exact(&mut node)?;
+
+        Ok(Some(node))
+    }
+
+    fn put(&mut self, hash: &Hash, node: &[u8]) -> io::Result<()> {
+        if self.index.contains_key(hash) {
+            return Ok(());
+        }
+
+        let record = encode_record(hash, node)?;
+        self.nodes.get_mut().write_all(&record)?;
+
+        self.index.insert(*hash, (self.end + RECORD_HEADER as u64, node_length(node.len())?));
+        self.end += record.len() as u64;
+
+        Ok(())
+    }
+
+    fn node_count(&self) -> usize {
+        self.index.len()
+    }
+
+    fn roots(&self) -> &[Hash] {
+        &self.roots
+    }
+
+    fn push_root(&mut self, root: &Hash) -> io::Result<()> {
+        self.nodes.get_mut().sync_data()?;
+        self.roots_file.write_all(root)?;
+        self.roots_file.sync_data()?;
+        self.roots.push(*root);
+
+        Ok(())
+    }
+
+    fn retain(&mut self, live: &HashSet<Hash>, roots: &[Hash]) -> io::Result<PruneReport> {
+        let mut report = PruneReport::default();
+        let nodes_path = self.dir.join(NODES_FILE);
+        let roots_path = self.dir.join(ROOTS_FILE);
+        let compact_nodes = self.dir.join(format!("{}{}", NODES_FILE, COMPACT_SUFFIX));
+        let compact_roots = self.dir.join(format!("{}{}", ROOTS_FILE, COMPACT_SUFFIX));
+
+        let mut compact = File::create(&compact_nodes)?;
+        let mut compacted_length = 0u64;
+        for hash in self.index.keys() {
+            if !live.contains(hash) {
+                report.nodes_freed += 1;
+                continue;
+            }
+
+            let node = self.get(hash)?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Indexed node not found"))?;
+            let record = encode_record(hash, &node)?;
+            compact.write_all(&record)?;
+            compacted_length += record.len() as u64;
+        }
+        compact.sync_all()?;
+        report.bytes_freed = self.end - compacted_length;
+
+        let mut compact = File::create(&compact_roots)?;
+        for root in roots {
+            compact.write_all(root)?;
+        }
+        compact.sync_all()?;
+
+        // Roots first: a crash in between leaves fewer roots over more nodes
+        fs::rename(&compact_roots, &roots_path)?;
+        fs::rename(&compact_nodes, &nodes_path)?;
+        let dir = self.dir.clone();
+        *self = FileStore::open(dir)?;
+
+        Ok(report)
+    }
+}
+
+fn encode_record(hash: &Hash, node: &[u8]) -> io::Result<Vec<u8>> {
+    let mut record = Vec::with_capacity(RECORD_HEADER + node.len());
+    record.extend_from_slice(hash);
+    record.extend_from_slice(&node_length(node.len())?.to_be_bytes());
+    record.extend_from_slice(node);
+
+    Ok(record)
+}
+
+/**
+ * Length of the node in its record header, which cannot hold 4 GiB or more.
+ */
+fn node_length(length: usize) -> io::Result<u32> {
+    u32::try_from(length).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Node too long for a record"))
+}
+
+/**
+ * Index of the complete records of the node log, and the end of the last one.
+ */
+fn scan_nodes(file: &mut File) -> io::Result<(Index, u64)> {
+    let length = file.metadata()?.len();
+    let mut index = HashMap::new();
+    let mut offset = 0u64;
+    let mut header = [0u8; RECORD_HEADER];
+
+    file.seek(SeekFrom::Start(0))?;
+    while offset + RECORD_HEADER as u64 <= length {
+        file.read_exact(&mut header)?;
+        let hash: Hash = header[..32].try_into().unwrap();
+        let node_length = u32::from_be_bytes(header[32..].try_into().unwrap());
+
+        let start = offset + RECORD_HEADER as u64;
+        if start + node_length as u64 > length {
+            break;
+        }
+        index.insert(hash, (start, node_length));
+
+        offset = file.seek(SeekFrom::Current(node_length as i64))?;
+    }
+
+    Ok((index, offset))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    /**
+     * Empty directory private to the test, removed on drop.
+     */
+    pub struct TestDir(pub PathBuf);
+    impl TestDir {
+        pub fn new(name: &str) -> TestDir {
+            let dir = env::temp_dir().join(format!("civisgrid-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn hash(i: u8) -> Hash {
+        [i; 32]
+    }
+
+    fn check_store<S: NodeStore>(store: &mut S) {
+        assert_eq!(store.get(&hash(1)).unwrap(), None);
+
+        store.put(&hash(1), b"first").unwrap();
+        store.put(&hash(2), b"").unwrap();
+        store.put(&hash(1), b"ignored").unwrap();
+        assert_eq!(store.get(&hash(1)).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(store.get(&hash(2)).unwrap(), Some(Vec::new()));
+        assert_eq!(store.node_count(), 2);
+
+        store.push_root(&hash(1)).unwrap();
+        store.push_root(&hash(2)).unwrap();
+        assert_eq!(store.roots(), &[hash(1), hash(2)]);
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = TestDir::new("file-store");
+        check_store(&mut FileStore::open(&dir.0).unwrap());
+
+        // Everything survives a restart
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.node_count(), 2);
+        assert_eq!(store.get(&hash(1)).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(store.roots(), &[hash(1), hash(2)]);
+
+        store.put(&hash(3), b"third").unwrap();
+        assert_eq!(store.get(&hash(3)).unwrap(), Some(b"third".to_vec()));
+        assert_eq!(store.get(&hash(1)).unwrap(), Some(b"first".to_vec()));
+    }
+
+    #[test]
+    fn node_lengths() {
+        assert_eq!(node_length(0).unwrap(), 0);
+        assert_eq!(node_length(u32::MAX as usize).unwrap(), u32::MAX);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(node_length(u32::MAX as usize + 1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn file_store_torn_writes() {
+        let dir = TestDir::new("torn-writes");
+        {
+            let mut store = FileStore::open(&dir.0).unwrap();
+            store.put(&hash(1), b"complete").unwrap();
+            store.push_root(&hash(1)).unwrap();
+        }
+
+        // A crash in the middle of a node record and of a root
+        let mut nodes = OpenOptions::new().append(true).open(dir.0.join(NODES_FILE)).unwrap();
+        nodes.write_all(&hash(2)).unwrap();
+        nodes.write_all(&100u32.to_be_bytes()).unwrap();
+        nodes.write_all(b"cut short").unwrap();
+        let mut roots = OpenOptions::new().append(true).open(dir.0.join(ROOTS_FILE)).unwrap();
+        roots.write_all(&hash(2)[..10]).unwrap();
+
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.node_count(), 1);
+        assert_eq!(store.get(&hash(2)).unwrap(), None);
+        assert_eq!(store.roots(), &[hash(1)]);
+
+        // Later records are readable again
+        store.put(&hash(3), b"after").unwrap();
+        store.push_root(&hash(3)).unwrap();
+        let store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.get(&hash(3)).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(store.get(&hash(1)).unwrap(), Some(b"complete".to_vec()));
+        assert_eq!(store.roots(), &[hash(1), hash(3)]);
+    }
+
+    #[test]
+    fn retention() {
+        let roots: Vec<Hash> = (0..5).map(hash).collect();
+
+        assert_eq!(Retention::LastRoots(2).kept_roots(&roots), vec![hash(3), hash(4)]);
+        assert_eq!(Retention::LastRoots(0).kept_roots(&roots), vec![hash(4)]);
+        assert_eq!(Retention::LastRoots(10).kept_roots(&roots), roots);
+
+        let pinned: HashSet<Hash> = [hash(1), hash(9)].iter().cloned().collect();
+        assert_eq!(Retention::Pinned(pinned).kept_roots(&roots), vec![hash(1), hash(4)]);
+        assert_eq!(Retention::Pinned(HashSet::new()).kept_roots(&[]), Vec::<Hash>::new());
+    }
+
+    fn check_retain<S: NodeStore>(store: &mut S) -> PruneReport {
+        for i in 0..10 {
+            store.put(&hash(i), &[i; 10]).unwrap();
+            store.push_root(&hash(i)).unwrap();
+        }
+
+        let live: HashSet<Hash> = (5..10).map(hash).collect();
+        let report = store.retain(&live, &[hash(8), hash(9)]).unwrap();
+        assert_eq!(report.nodes_freed, 5);
+        assert_eq!(store.node_count(), 5);
+        assert_eq!(store.get(&hash(4)).unwrap(), None);
+        assert_eq!(store.get(&hash(5)).unwrap(), Some(vec![5; 10]));
+        assert_eq!(store.roots(), &[hash(8), hash(9)]);
+
+        // Nothing left to free
+        assert_eq!(store.retain(&live, &[hash(9)]).unwrap(), PruneReport::default());
+
+        report
+    }
+
+    #[test]
+    fn memory_store_retain() {
+        let report = check_retain(&mut MemoryStore::new());
+        assert_eq!(report.bytes_freed, 5 * 10);
+    }
+
+    #[test]
+    fn file_store_retain() {
+        let dir = TestDir::new("file-store-retain");
+        let report = check_retain(&mut FileStore::open(&dir.0).unwrap());
+        assert_eq!(report.bytes_freed, 5 * (RECORD_HEADER as u64 + 10));
+
+        // The compacted files are the store on restart, and can be appended to
+        let mut store = FileStore::open(&dir.0).unwrap();
+        assert_eq!(store.node_count(), 5);
+        assert_eq!(store.roots(), &[hash(9)]);
+        assert_eq!(fs::metadata(dir.0.join(NODES_FILE)).unwrap().len(), 5 * (RECORD_HEADER as u64 + 10));
+        assert!(!dir.0.join(format!("{}{}", NODES_FILE, COMPACT_SUFFIX)).exists());
+
+        store.put(&hash(20), b"new").unwrap();
+        assert_eq!(store.get(&hash(20)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(&hash(7)).unwrap(), Some(vec![7; 10]));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::merkletree::{MerkleError, MerkleHasher, MerkleTree, Proof, to_hex};
+use crate::merkletree::level_pairs;
+
+/**
+ * Hex characters of a hash shown in diagrams.
+ */
+const SHORT_HASH: usize = 8;
+
+/**
+ * Node of the drawn tree: its level and position where it was hashed.
+ * A node carried up unchanged is drawn once, at its own level.
+ */
+type NodeId = (usize, usize);
+
+/**
+ * Nodes on the proven leaf's way up to the root, and the siblings the proof carries.
+ */
+struct Highlight {
+    path: HashSet<NodeId>,
+    siblings: HashSet<NodeId>,
+}
+
+/**
+ * The tree as a Graphviz digraph, root at the top and leaves in order at the bottom,
+ * e.g. `dot -Tsvg`. Nodes show a truncated hash, the full one is their tooltip.
+ *
+ * With a proof of this tree, the path from the leaf to the root is filled in
+ * yellow and the siblings hashed along it in blue. A proof whose siblings are
+ * not the tree's nodes is a MalformedProof.
+ */
+pub fn to_dot<H: MerkleHasher>(tree: &MerkleTree<H>, proof: Option<&Proof>) -> Result<String, MerkleError> {
+    let shape = Shape::of(tree);
+    let highlight = proof.map(|proof| shape.highlight(tree, proof)).transpose()?;
+    let mut dot = String::from("digraph merkle {\n    ordering=out;\n    node [shape=box, fontname=\"monospace\"];\n");
+
+    let mut pending = vec![shape.root()];
+    while let Some(node) = pending.pop() {
+        let hash = to_hex(tree.node(node.0, node.1).unwrap()); // resolved nodes are all stored
+        let label = match node.0 {
+            0 => format!("leaf {}\\n{}", node.1, &hash[..SHORT_HASH]),
+            _ => hash[..SHORT_HASH].to_string(),
+        };
+        let fill = match &highlight {
+            Some(highlight) if highlight.path.contains(&node) => ", style=filled, fillcolor=\"#ffd966\"",
+            Some(highlight) if highlight.siblings.contains(&node) => ", style=filled, fillcolor=\"#9fc5e8\"",
+            _ => "",
+        };
+        writeln!(dot, "    {} [label=\"{}\", tooltip=\"{}\"{}];", dot_id(node), label, hash, fill).unwrap();
+
+        let children = shape.children(node);
+        for child in &children {
+            let bold = match &highlight {
+                Some(highlight) if highlight.path.contains(child) => " [penwidth=3]",
+                _ => "",
+            };
+            writeln!(dot, "    {} -> {}{};", dot_id(node), dot_id(*child), bold).unwrap();
+        }
+        pending.extend(children.iter().rev());
+    }
+
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+/**
+ * The tree as indented lines, root first and each branch followed by its
+ * left then right subtree, with truncated hashes:
+ *
+ * ```text
+ * 1f0c8a2b
+ * +-- 7d41e9c0
+ * |   +-- 0 9a3c11f2
+ * |   \-- 1 44b0d7e6
+ * \-- 2 c2e85a13
+ * ```
+ *
+ * Leaves start with their index. With a proof of this tree, nodes on the
+ * leaf's path end with `<= path` and the siblings hashed along it with `<- sibling`.
+ * The proof is checked as for to_dot.
+ */
+pub fn to_ascii<H: MerkleHasher>(tree: &MerkleTree<H>, proof: Option<&Proof>) -> Result<String, MerkleError> {
+    let shape = Shape::of(tree);
+    let highlight = proof.map(|proof| shape.highlight(tree, proof)).transpose()?;
+    let mut ascii = String::new();
+
+    // Nodes with the prefix of their line and of their children's lines
+    let mut pending = vec![(shape.root(), String::new(), String::new())];
+    while let Some((node, line_prefix, child_prefix)) = pending.pop() {
+        let hash = to_hex(tree.node(node.0, node.1).unwrap());
+        ascii.push_str(&line_prefix);
+        if node.0 == 0 {
+            write!(ascii, "{} ", node.1).unwrap();
+        }
+        ascii.push_str(&hash[..SHORT_HASH]);
+        match &highlight {
+            Some(highlight) if highlight.path.contains(&node) => ascii.push_str(" <= path"),
+            Some(highlight) if highlight.siblings.contains(&node) => ascii.push_str(" <- sibling"),
+            _ => {}
+        }
+        ascii.push('\n');
+
+        if let [left, right] = shape.children(node)[..] {
+            pending.push((right, format!("{}\\-- ", child_prefix), format!("{}    ", child_prefix)));
+            pending.push((left, format!("{}+-- ", child_prefix), format!("{}|   ", child_prefix)));
+        }
+    }
+
+    Ok(ascii)
+}
+
+/**
+ * Which nodes of the stored levels are hashed, and which are carried up.
+ */
+struct Shape {
+    pairs: Vec<usize>,
+    leaf_count: usize,
+}
+
+impl Shape {
+    fn of<H: MerkleHasher>(tree: &MerkleTree<H>) -> Shape {
+        Shape{
+            pairs: level_pairs(tree.layout(), tree.leaf_count()),
+            leaf_count: tree.leaf_count(),
+        }
+    }
+
+    fn root(&self) -> NodeId {
+        self.resolve(self.pairs.len(), 0)
+    }
+
+    /**
+     * Node stored at `position` of `level`, followed down while it was carried up.
+     */
+    fn resolve(&self, mut level: usize, mut position: usize) -> NodeId {
+        while level > 0 && position >= self.pairs[level - 1] {
+            position += self.pairs[level - 1];
+            level -= 1;
+        }
+
+        (level, position)
+    }
+
+    fn children(&self, (level, position): NodeId) -> Vec<NodeId> {
+        match level {
+            0 => Vec::new(),
+            _ => vec![self.resolve(level - 1, 2 * position), self.resolve(level - 1, 2 * position + 1)],
+        }
+    }
+
+    /**
+     * Only a proof whose path is made of this tree's nodes is drawn.
+     */
+    fn highlight<H: MerkleHasher>(&self, tree: &MerkleTree<H>, proof: &Proof) -> Result<Highlight, MerkleError> {
+        if proof.leaf_count != self.leaf_count {
+            return Err(MerkleError::SizeMismatch{size: proof.leaf_count, available: self.leaf_count});
+        }
+        if proof.leaf_index >= self.leaf_count {
+            return Err(MerkleError::UnknownLeaf{index: proof.leaf_index, leaf_count: self.leaf_count});
+        }
+        if level_pairs(proof.layout, proof.leaf_count) != self.pairs {
+            return Err(MerkleError::IncompatibleTrees);
+        }
+
+        let mut highlight = Highlight{
+            path: [(0, proof.leaf_index)].iter().cloned().collect(),
+            siblings: HashSet::new(),
+        };
+        let mut steps = proof.path.iter();
+        let mut position = proof.leaf_index;
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            if position < 2 * pairs {
+                let sibling = self.resolve(level, position ^ 1);
+                match steps.next() {
+                    Some(step) if tree.node(sibling.0, sibling.1) == Some(&step.hash) => {}
+                    _ => return Err(MerkleError::MalformedProof),
+                }
+                highlight.siblings.insert(sibling);
+                position /= 2;
+                highlight.path.insert((level + 1, position));
+            } else {
+                position -= pairs;
+            }
+        }
+        if steps.next().is_some() {
+            return Err(MerkleError::MalformedProof);
+        }
+
+        Ok(highlight)
+    }
+}
+
+fn dot_id((level, position): NodeId) -> String {
+    format!("n{}_{}", level, position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkletree::{HashScheme, Layout, Sha3_256};
+
+    fn make_meters(amount: usize) -> Vec<Vec<u8>> {
+        (0..amount).map(|i| format!("Meter {}: {} kWh", i, 10 * i).into_bytes()).collect()
+    }
+
+    fn short(hash: &[u8; 32]) -> String {
+        to_hex(hash)[..SHORT_HASH].to_string()
+    }
+
+    #[test]
+    fn ascii() {
+        // Level sizes 5, 3, 2, 1: leaf 4 is carried up to the root's right child
+        let tree = MerkleTree::from_data(&make_meters(5)).unwrap();
+        let node = |level, position| short(tree.node(level, position).unwrap());
+
+        let expected = [
+            node(3, 0),
+            format!("+-- {}", node(2, 0)),
+            format!("|   +-- {}", node(1, 0)),
+            format!("|   |   +-- 0 {}", node(0, 0)),
+            format!("|   |   \\-- 1 {}", node(0, 1)),
+            format!("|   \\-- {}", node(1, 1)),
+            format!("|       +-- 2 {}", node(0, 2)),
+            format!("|       \\-- 3 {}", node(0, 3)),
+            format!("\\-- 4 {}", node(0, 4)),
+        ];
+        assert_eq!(to_ascii(&tree, None).unwrap(), expected.join("\n") + "\n");
+
+        let highlighted = to_ascii(&tree, Some(&tree.make_proof(2).unwrap())).unwrap();
+        let marked: Vec<&str> = highlighted.lines().map(|line| line.rsplit(' ').next().unwrap()).collect();
+        assert_eq!(marked, ["path", "path", "sibling", node(0, 0).as_str(), node(0, 1).as_str(), "path", "path", "sibling", "sibling"]);
+
+        let single = MerkleTree::from_data(&make_meters(1)).unwrap();
+        assert_eq!(to_ascii(&single, None).unwrap(), format!("0 {}\n", short(single.root())));
+    }
+
+    #[test]
+    fn dot() {
+        for leaves_count in 1..=20 {
+            for layout in [Layout::Balanced, Layout::Complete] {
+                let tree = MerkleTree::<Sha3_256>::build(&make_meters(leaves_count), HashScheme::Rfc6962, layout).unwrap();
+                let dot = to_dot(&tree, None).unwrap();
+
+                // Every node once, an edge to each but the root
+                assert!(dot.starts_with("digraph merkle {") && dot.ends_with("}\n"));
+                assert_eq!(dot.matches("label=").count(), 2 * leaves_count - 1);
+                assert_eq!(dot.matches(" -> ").count(), 2 * leaves_count - 2);
+                assert!(dot.contains(&to_hex(tree.root())));
+
+                for index in 0..leaves_count {
+                    let proof = tree.make_proof(index).unwrap();
+                    let dot = to_dot(&tree, Some(&proof)).unwrap();
+                    assert_eq!(dot.matches("#9fc5e8").count(), proof.path.len());
+                    assert_eq!(dot.matches("#ffd966").count(), proof.path.len() + 1);
+                    assert_eq!(dot.matches("penwidth").count(), proof.path.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn foreign_proofs() {
+        let tree = MerkleTree::from_data(&make_meters(6)).unwrap();
+        let other = MerkleTree::from_data(&make_meters(7)).unwrap();
+        let complete = MerkleTree::complete_from_data(&make_meters(6)).unwrap();
+
+        let proof = other.make_proof(6).unwrap();
+        assert!(matches!(to_ascii(&tree, Some(&proof)), Err(MerkleError::SizeMismatch{..})));
+        let proof = complete.make_proof(0).unwrap();
+        assert!(matches!(to_dot(&tree, Some(&proof)), Err(MerkleError::IncompatibleTrees)));
+
+        let mut proof = tree.make_proof(5).unwrap();
+        proof.leaf_index = 6;
+        assert!(matches!(to_dot(&tree, Some(&proof)), Err(MerkleError::UnknownLeaf{..})));
+
+        // Right index and size, but siblings that are not the tree's
+        let mut proof = tree.make_proof(2).unwrap();
+        proof.path[1].hash[0] ^= 1;
+        assert!(matches!(to_ascii(&tree, Some(&proof)), Err(MerkleError::MalformedProof)));
+        assert!(matches!(to_dot(&tree, Some(&proof)), Err(MerkleError::MalformedProof)));
+        let mut proof = tree.make_proof(2).unwrap();
+        proof.path.pop();
+        assert!(matches!(to_ascii(&tree, Some(&proof)), Err(MerkleError::MalformedProof)));
+        let mut proof = tree.make_proof(2).unwrap();
+        proof.path.push(proof.path[0].clone());
+        assert!(matches!(to_dot(&tree, Some(&proof)), Err(MerkleError::MalformedProof)));
+
+        // A proof of another leaf, relabelled
+        let mut proof = tree.make_proof(3).unwrap();
+        proof.leaf_index = 0;
+        assert!(matches!(to_dot(&tree, Some(&proof)), Err(MerkleError::MalformedProof)));
+    }
+}
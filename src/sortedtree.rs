@@ -0,0 +1,277 @@
+use serde::{Serialize, Deserialize};
+
+use crate::merkletree::{Hash, HashScheme, Layout, MerkleError, MerkleHasher, MerkleTree, Proof, Sha3_256};
+use crate::merkletree::verify_proof_with_scheme;
+
+/**
+ * Entry of a sorted tree with its inclusion proof.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryProof {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub proof: Proof,
+}
+
+/**
+ * Proof that a key is not in a sorted tree: the two adjacent entries whose
+ * keys bracket it. There is no `left` entry when the key is before the first
+ * one, and no `right` entry when it is after the last one.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonInclusionProof {
+    pub left: Option<EntryProof>,
+    pub right: Option<EntryProof>,
+}
+
+/**
+ * Merkle tree of key/value entries sorted by key, e.g. the order IDs of a
+ * clearing round, so that a key can also be proven absent.
+ *
+ * A leaf is the key length (u32 BE), the key and the value, in a balanced
+ * MerkleTree with the Rfc6962 scheme. Keys are distinct.
+ * Non-inclusion proofs are only sound if the tree behind the root is sorted,
+ * which the publisher of the root vouches for like for the rest of its content.
+ */
+pub struct SortedMerkleTree<H = Sha3_256> {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    tree: MerkleTree<H>,
+}
+
+impl SortedMerkleTree {
+    pub fn from_entries<K: AsRef<[u8]>, V: AsRef<[u8]>>(entries: &[(K, V)]) -> Result<SortedMerkleTree, MerkleError> {
+        SortedMerkleTree::build(entries)
+    }
+}
+
+impl<H: MerkleHasher> SortedMerkleTree<H> {
+    /**
+     * Entries can be given in any order, they are sorted by key.
+     */
+    pub fn build<K: AsRef<[u8]>, V: AsRef<[u8]>>(entries: &[(K, V)]) -> Result<Self, MerkleError> {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = entries.iter()
+            .map(|(key, value)| (key.as_ref().to_vec(), value.as_ref().to_vec()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(MerkleError::KeyExists);
+        }
+
+        let leaves: Vec<Vec<u8>> = entries.iter().map(|(key, value)| encode_entry(key, value)).collect();
+        let tree = MerkleTree::build(&leaves, HashScheme::Rfc6962, Layout::Balanced)?;
+
+        Ok(SortedMerkleTree{
+            entries,
+            tree,
+        })
+    }
+
+    pub fn root(&self) -> &Hash {
+        self.tree.root()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.search(key).ok().map(|index| &self.entries[index].1[..])
+    }
+
+    pub fn prove_inclusion(&self, key: &[u8]) -> Result<EntryProof, MerkleError> {
+        match self.search(key) {
+            Ok(index) => self.entry_proof(index),
+            Err(_) => Err(MerkleError::UnknownKey),
+        }
+    }
+
+    pub fn prove_non_inclusion(&self, key: &[u8]) -> Result<NonInclusionProof, MerkleError> {
+        let index = match self.search(key) {
+            Ok(_) => return Err(MerkleError::KeyExists),
+            Err(index) => index, // first entry after the key
+        };
+
+        let left = match index {
+            0 => None,
+            _ => Some(self.entry_proof(index - 1)?),
+        };
+        let right = match index < self.entries.len() {
+            true => Some(self.entry_proof(index)?),
+            false => None,
+        };
+
+        Ok(NonInclusionProof{
+            left,
+            right,
+        })
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(entry, _)| entry[..].cmp(key))
+    }
+
+    fn entry_proof(&self, index: usize) -> Result<EntryProof, MerkleError> {
+        let (key, value) = &self.entries[index];
+
+        Ok(EntryProof{
+            key: key.clone(),
+            value: value.clone(),
+            proof: self.tree.make_proof(index)?,
+        })
+    }
+}
+
+/**
+ * Checks the entry's inclusion proof against the root of a sorted tree.
+ */
+pub fn verify_entry<H: MerkleHasher>(root: &Hash, entry: &EntryProof) -> bool {
+    entry.proof.layout == Layout::Balanced
+        && verify_proof_with_scheme::<H>(HashScheme::Rfc6962, root, &encode_entry(&entry.key, &entry.value), &entry.proof)
+}
+
+/**
+ * Checks that both entries are in the tree, are adjacent and bracket `key`.
+ *
+ * Proofs bind their leaf index to the path, so the adjacency of the indices
+ * is that of the leaves: the left one is the rightmost leaf of the left
+ * subtree of a node, the right one the leftmost leaf of its right subtree.
+ * Likewise an entry is the first (last) leaf only if all its siblings are on its right (left).
+ */
+pub fn verify_non_inclusion<H: MerkleHasher>(root: &Hash, key: &[u8], proof: &NonInclusionProof) -> bool {
+    if let Some(left) = &proof.left {
+        if !verify_entry::<H>(root, left) || left.key[..] >= *key {
+            return false;
+        }
+    }
+    if let Some(right) = &proof.right {
+        if !verify_entry::<H>(root, right) || right.key[..] <= *key {
+            return false;
+        }
+    }
+
+    match (&proof.left, &proof.right) {
+        (Some(left), Some(right)) => {
+            left.proof.leaf_count == right.proof.leaf_count
+                && left.proof.leaf_index + 1 == right.proof.leaf_index
+        }
+        (Some(last), None) => last.proof.leaf_index + 1 == last.proof.leaf_count,
+        (None, Some(first)) => first.proof.leaf_index == 0,
+        (None, None) => false,
+    }
+}
+
+fn encode_entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+    [&(key.len() as u32).to_be_bytes()[..], key, value].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_id(i: usize) -> Vec<u8> {
+        format!("order-{:04}", i).into_bytes()
+    }
+
+    /**
+     * Orders with odd IDs only, in reverse order.
+     */
+    fn make_round(orders: usize) -> SortedMerkleTree {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..orders).rev()
+            .map(|i| (order_id(2 * i + 1), format!("{} kWh", i).into_bytes()))
+            .collect();
+        SortedMerkleTree::from_entries(&entries).unwrap()
+    }
+
+    #[test]
+    fn sorted_entries() {
+        let tree = make_round(10);
+        assert_eq!(tree.leaf_count(), 10);
+        assert_eq!(tree.get(&order_id(5)), Some(&b"2 kWh"[..]));
+        assert_eq!(tree.get(&order_id(4)), None);
+
+        // The root does not depend on the order of the entries
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..10).map(|i| (order_id(2 * i + 1), format!("{} kWh", i).into_bytes())).collect();
+        assert_eq!(SortedMerkleTree::from_entries(&entries).unwrap().root(), tree.root());
+
+        assert!(matches!(SortedMerkleTree::from_entries(&[(b"a", b"1"), (b"a", b"2")]), Err(MerkleError::KeyExists)));
+        assert!(matches!(SortedMerkleTree::from_entries::<&[u8], &[u8]>(&[]), Err(MerkleError::EmptyInput)));
+    }
+
+    #[test]
+    fn inclusion_proofs() {
+        let tree = make_round(9);
+        for i in 0..9 {
+            let proof = tree.prove_inclusion(&order_id(2 * i + 1)).unwrap();
+            assert!(verify_entry::<Sha3_256>(tree.root(), &proof));
+
+            let mut forged = proof.clone();
+            forged.value = b"-1 kWh".to_vec();
+            assert!(!verify_entry::<Sha3_256>(tree.root(), &forged));
+        }
+        assert!(matches!(tree.prove_inclusion(&order_id(2)), Err(MerkleError::UnknownKey)));
+    }
+
+    #[test]
+    fn non_inclusion_proofs() {
+        for orders in 1..=12 {
+            let tree = make_round(orders);
+
+            // Before the first, between each pair and after the last order
+            for i in 0..=orders {
+                let missing = order_id(2 * i);
+                let proof = tree.prove_non_inclusion(&missing).unwrap();
+                assert_eq!(proof.left.is_some(), i > 0);
+                assert_eq!(proof.right.is_some(), i < orders);
+                assert!(verify_non_inclusion::<Sha3_256>(tree.root(), &missing, &proof));
+
+                // The same proof does not hold for the bracketing keys
+                if let Some(left) = &proof.left {
+                    assert!(!verify_non_inclusion::<Sha3_256>(tree.root(), &left.key, &proof));
+                }
+                if let Some(right) = &proof.right {
+                    assert!(!verify_non_inclusion::<Sha3_256>(tree.root(), &right.key, &proof));
+                }
+            }
+            assert!(matches!(tree.prove_non_inclusion(&order_id(1)), Err(MerkleError::KeyExists)));
+        }
+
+        let before = make_round(5).prove_non_inclusion(b"").unwrap();
+        assert!(before.left.is_none() && before.right.is_some());
+    }
+
+    #[test]
+    fn forged_non_inclusion() {
+        let tree = make_round(8);
+        let root = tree.root();
+        let present = order_id(7);
+
+        // Hiding an order with its non-adjacent neighbours
+        let proof = NonInclusionProof{
+            left: Some(tree.prove_inclusion(&order_id(5)).unwrap()),
+            right: Some(tree.prove_inclusion(&order_id(9)).unwrap()),
+        };
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &present, &proof));
+
+        // Claiming adjacent indices on the real paths
+        let mut moved = proof.clone();
+        moved.right.as_mut().unwrap().proof.leaf_index = 3;
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &present, &moved));
+
+        // Hiding the first or last orders with a single entry
+        let first = NonInclusionProof{left: None, right: Some(tree.prove_inclusion(&order_id(3)).unwrap())};
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &order_id(1), &first));
+        let last = NonInclusionProof{left: Some(tree.prove_inclusion(&order_id(13)).unwrap()), right: None};
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &order_id(15), &last));
+
+        // Shrinking the tree to make an entry look last
+        let mut shrunk = last.clone();
+        shrunk.left.as_mut().unwrap().proof.leaf_count = 7;
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &order_id(15), &shrunk));
+
+        assert!(!verify_non_inclusion::<Sha3_256>(root, &order_id(1), &NonInclusionProof{left: None, right: None}));
+
+        let proof = tree.prove_non_inclusion(&order_id(4)).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<NonInclusionProof>(&json).unwrap(), proof);
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use serde::{Serialize, Deserialize};
+
+use crate::merkletree::{Hash, HashScheme, MerkleError, MerkleHasher, Sha3_256, hex_hash, hex_hashes};
+use crate::merkletree::{LEAF_PREFIX, BRANCH_PREFIX};
+use crate::nodestore::{NodeStore, MemoryStore, PruneReport, Retention};
+
+/**
+ * Number of levels below the root: one per bit of a key.
+ */
+pub const DEPTH: usize = 256;
+
+/**
+ * Hash of a leaf with no value.
+ */
+const EMPTY_LEAF: Hash = [0u8; 32];
+
+/**
+ * Proof that a key is bound to a value, or to no value at all, under a root.
+ *
+ * Most siblings are roots of empty subtrees, which the verifier can compute:
+ * bit d of `bitmap` (most significant first) is set when the sibling at depth d
+ * is not empty, and only those are listed in `siblings`, from the root down.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseProof {
+    #[serde(with = "hex_hash")]
+    pub bitmap: Hash,
+    #[serde(with = "hex_hashes")]
+    pub siblings: Vec<Hash>,
+}
+
+/**
+ * Sparse Merkle tree over 256-bit keys, e.g. participant account IDs.
+ *
+ * Every key has its own leaf at depth 256, following the bits of the key from
+ * the most significant. Absent keys have an empty leaf, so the tree is mostly
+ * made of empty subtrees, whose roots only depend on their height and are not stored.
+ *
+ * Leaves are H(0x00 || key || value), branches H(0x01 || left || right), and
+ * are stored by hash as these very bytes. A set or delete costs 256 hashes and
+ * records the new root in the store, see NodeStore.
+ */
+pub struct SparseMerkleTree<H = Sha3_256, S = MemoryStore> {
+    store: S,
+    empty: Vec<Hash>, // empty[h]: root of an empty subtree of height h
+    hasher: PhantomData<fn() -> H>,
+}
+impl<H: MerkleHasher> Default for SparseMerkleTree<H> {
+    fn default() -> Self {
+        SparseMerkleTree{
+            store: MemoryStore::new(),
+            empty: empty_roots::<H>(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> SparseMerkleTree {
+        SparseMerkleTree::default()
+    }
+}
+
+impl<H: MerkleHasher, S: NodeStore> SparseMerkleTree<H, S> {
+    /**
+     * Tree over the nodes of `store`, starting from its last root if any.
+     */
+    pub fn with_store(store: S) -> Self {
+        SparseMerkleTree{
+            store,
+            empty: empty_roots::<H>(),
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn root(&self) -> &Hash {
+        self.store.roots().last().unwrap_or(&self.empty[DEPTH])
+    }
+
+    pub fn get(&self, key: &Hash) -> Result<Option<Vec<u8>>, MerkleError> {
+        let (_, leaf) = self.path(key)?;
+        if leaf == EMPTY_LEAF {
+            return Ok(None);
+        }
+
+        let node = self.node(&leaf)?;
+        match node.first() {
+            Some(&LEAF_PREFIX) if node.len() >= 33 => Ok(Some(node[33..].to_vec())),
+            _ => Err(MerkleError::CorruptNode(leaf)),
+        }
+    }
+
+    pub fn set(&mut self, key: &Hash, value: &[u8]) -> Result<(), MerkleError> {
+        let node = [&[LEAF_PREFIX], &key[..], value].concat();
+        let leaf = H::hash(&[&node]);
+        self.store.put(&leaf, &node)?;
+
+        self.update(key, leaf)
+    }
+
+    /**
+     * Returns the value the key was bound to, if any.
+     */
+    pub fn delete(&mut self, key: &Hash) -> Result<Option<Vec<u8>>, MerkleError> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.update(key, EMPTY_LEAF)?;
+        }
+
+        Ok(value)
+    }
+
+    /**
+     * Membership proof if the key has a value, non-membership proof otherwise.
+     */
+    pub fn prove(&self, key: &Hash) -> Result<SparseProof, MerkleError> {
+        let (path, _) = self.path(key)?;
+        let mut bitmap = [0u8; 32];
+        let mut siblings = Vec::new();
+
+        for (depth, sibling) in path.iter().enumerate() {
+            if *sibling != self.empty[DEPTH - depth - 1] {
+                bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                siblings.push(*sibling);
+            }
+        }
+
+        Ok(SparseProof{
+            bitmap,
+            siblings,
+        })
+    }
+
+    /**
+     * Keeps the roots chosen by `retention`, see MerkleTrie::prune.
+     * Every set or delete is a root of its own.
+     */
+    pub fn prune(&mut self, retention: &Retention) -> Result<PruneReport, MerkleError> {
+        let roots = retention.kept_roots(self.store.roots());
+        let mut live = HashSet::new();
+        let mut pending: Vec<(Hash, usize)> = roots.iter().map(|root| (*root, DEPTH)).collect();
+
+        while let Some((hash, height)) = pending.pop() {
+            if hash == self.empty[height] || !live.insert(hash) {
+                continue;
+            }
+            if height > 0 {
+                let (left, right) = self.children(&hash, height)?;
+                pending.push((left, height - 1));
+                pending.push((right, height - 1));
+            }
+        }
+
+        Ok(self.store.retain(&live, &roots)?)
+    }
+
+    /**
+     * Siblings of the key's path from the root down, and the key's leaf.
+     */
+    fn path(&self, key: &Hash) -> Result<(Vec<Hash>, Hash), MerkleError> {
+        let mut path = Vec::with_capacity(DEPTH);
+        let mut node = *self.root();
+
+        for depth in 0..DEPTH {
+            let (left, right) = self.children(&node, DEPTH - depth)?;
+            match bit(key, depth) {
+                false => { path.push(right); node = left; }
+                true => { path.push(left); node = right; }
+            }
+        }
+
+        Ok((path, node))
+    }
+
+    fn children(&self, hash: &Hash, height: usize) -> Result<(Hash, Hash), MerkleError> {
+        if *hash == self.empty[height] {
+            return Ok((self.empty[height - 1], self.empty[height - 1]));
+        }
+
+        let node = self.node(hash)?;
+        match node.first() {
+            Some(&BRANCH_PREFIX) if node.len() == 65 => Ok((node[1..33].try_into().unwrap(), node[33..].try_into().unwrap())),
+            _ => Err(MerkleError::CorruptNode(*hash)),
+        }
+    }
+
+    fn node(&self, hash: &Hash) -> Result<Vec<u8>, MerkleError> {
+        self.store.get(hash)?.ok_or(MerkleError::MissingNode(*hash))
+    }
+
+    /**
+     * Sets the key's leaf and re-hashes its path up to a new root.
+     */
+    fn update(&mut self, key: &Hash, leaf: Hash) -> Result<(), MerkleError> {
+        let (path, _) = self.path(key)?;
+        let mut hash = leaf;
+
+        for (depth, sibling) in path.iter().enumerate().rev() {
+            let (left, right) = match bit(key, depth) {
+                false => (hash, *sibling),
+                true => (*sibling, hash),
+            };
+            let node = [&[BRANCH_PREFIX], &left[..], &right[..]].concat();
+            hash = H::hash(&[&node]);
+
+            // Empty subtrees are implied by their height
+            if hash != self.empty[DEPTH - depth] {
+                self.store.put(&hash, &node)?;
+            }
+        }
+
+        Ok(self.store.push_root(&hash)?)
+    }
+}
+
+/**
+ * Checks that `key` is bound to `value` under `root`, or to no value when `value` is None.
+ */
+pub fn verify_sparse_proof<H: MerkleHasher>(root: &Hash, key: &Hash, value: Option<&[u8]>, proof: &SparseProof) -> bool {
+    let set_bits: u32 = proof.bitmap.iter().map(|byte| byte.count_ones()).sum();
+    if set_bits as usize != proof.siblings.len() {
+        return false;
+    }
+
+    let empty = empty_roots::<H>();
+    let mut siblings = proof.siblings.iter().rev();
+    let mut hash = match value {
+        Some(value) => hash_leaf::<H>(key, value),
+        None => EMPTY_LEAF,
+    };
+
+    for depth in (0..DEPTH).rev() {
+        let sibling = match bit(&proof.bitmap, depth) {
+            true => *siblings.next().unwrap(),
+            false => empty[DEPTH - depth - 1],
+        };
+        hash = match bit(key, depth) {
+            false => HashScheme::Rfc6962.hash_branch::<H>(&hash, &sibling),
+            true => HashScheme::Rfc6962.hash_branch::<H>(&sibling, &hash),
+        };
+    }
+
+    hash == *root
+}
+
+fn hash_leaf<H: MerkleHasher>(key: &Hash, value: &[u8]) -> Hash {
+    H::hash(&[&[LEAF_PREFIX], key, value])
+}
+
+fn empty_roots<H: MerkleHasher>() -> Vec<Hash> {
+    let mut empty = vec![EMPTY_LEAF];
+    for height in 0..DEPTH {
+        let below = empty[height];
+        empty.push(HashScheme::Rfc6962.hash_branch::<H>(&below, &below));
+    }
+
+    empty
+}
+
+/**
+ * Bit at `index` of `key`, most significant first.
+ */
+fn bit(key: &Hash, index: usize) -> bool {
+    key[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkletree::Sha256;
+    use crate::nodestore::FileStore;
+    use crate::nodestore::tests::TestDir;
+
+    fn account(id: usize) -> Hash {
+        Sha3_256::hash(&[format!("Participant {}", id).as_bytes()])
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = SparseMerkleTree::new();
+        let key = account(0);
+
+        assert_eq!(*tree.root(), empty_roots::<Sha3_256>()[DEPTH]);
+        assert_eq!(tree.get(&key).unwrap(), None);
+
+        let proof = tree.prove(&key).unwrap();
+        assert!(proof.siblings.is_empty());
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &key, None, &proof));
+        assert!(!verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b""), &proof));
+    }
+
+    #[test]
+    fn get_set_delete() {
+        let mut tree = SparseMerkleTree::new();
+        let empty_root = *tree.root();
+
+        for id in 0..20 {
+            tree.set(&account(id), format!("{} kWh", id).as_bytes()).unwrap();
+        }
+        for id in 0..20 {
+            assert_eq!(tree.get(&account(id)).unwrap(), Some(format!("{} kWh", id).into_bytes()));
+        }
+        assert_eq!(tree.get(&account(20)).unwrap(), None);
+
+        // Overwriting changes the root, writing the same value back restores it
+        let root = *tree.root();
+        tree.set(&account(3), b"-1 kWh").unwrap();
+        assert_eq!(tree.get(&account(3)).unwrap(), Some(b"-1 kWh".to_vec()));
+        assert_ne!(*tree.root(), root);
+        tree.set(&account(3), b"3 kWh").unwrap();
+        assert_eq!(*tree.root(), root);
+
+        assert_eq!(tree.delete(&account(20)).unwrap(), None);
+        assert_eq!(*tree.root(), root);
+        assert_eq!(tree.delete(&account(7)).unwrap(), Some(b"7 kWh".to_vec()));
+        assert_eq!(tree.get(&account(7)).unwrap(), None);
+
+        // The root only depends on the content, not on the history
+        let mut other = SparseMerkleTree::new();
+        for id in (0..20).rev().filter(|id| *id != 7) {
+            other.set(&account(id), format!("{} kWh", id).as_bytes()).unwrap();
+        }
+        assert_eq!(other.root(), tree.root());
+
+        for id in 0..20 {
+            tree.delete(&account(id)).unwrap();
+        }
+        assert_eq!(*tree.root(), empty_root);
+    }
+
+    #[test]
+    fn membership_proofs() {
+        let mut tree = SparseMerkleTree::<Sha256>::default();
+        for id in 0..50 {
+            tree.set(&account(id), &id.to_be_bytes()).unwrap();
+        }
+        let root = *tree.root();
+
+        for id in 0..50 {
+            let key = account(id);
+            let proof = tree.prove(&key).unwrap();
+            assert!(verify_sparse_proof::<Sha256>(&root, &key, Some(&id.to_be_bytes()), &proof));
+            assert!(!verify_sparse_proof::<Sha256>(&root, &key, Some(&(id + 1).to_be_bytes()), &proof));
+            assert!(!verify_sparse_proof::<Sha256>(&root, &key, None, &proof));
+            assert!(!verify_sparse_proof::<Sha256>(&root, &account(id + 50), Some(&id.to_be_bytes()), &proof));
+            assert!(!verify_sparse_proof::<Sha3_256>(&root, &key, Some(&id.to_be_bytes()), &proof));
+
+            // About log2(50) non-empty siblings instead of 256
+            assert!(proof.siblings.len() < 20);
+        }
+    }
+
+    #[test]
+    fn non_membership_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        for id in 0..50 {
+            tree.set(&account(id), b"credits").unwrap();
+        }
+        let root = *tree.root();
+
+        for id in 50..100 {
+            let key = account(id);
+            let proof = tree.prove(&key).unwrap();
+            assert!(verify_sparse_proof::<Sha3_256>(&root, &key, None, &proof));
+            assert!(!verify_sparse_proof::<Sha3_256>(&root, &key, Some(b"credits"), &proof));
+        }
+
+        // A deleted key is proven absent, the old proof no longer verifies
+        let key = account(10);
+        let old_proof = tree.prove(&key).unwrap();
+        tree.delete(&key).unwrap();
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &key, None, &tree.prove(&key).unwrap()));
+        assert!(!verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b"credits"), &old_proof));
+        assert!(verify_sparse_proof::<Sha3_256>(&root, &key, Some(b"credits"), &old_proof));
+    }
+
+    #[test]
+    fn tampered_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        for id in 0..8 {
+            tree.set(&account(id), b"credits").unwrap();
+        }
+        let key = account(1);
+        let proof = tree.prove(&key).unwrap();
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b"credits"), &proof));
+
+        let mut flipped = proof.clone();
+        flipped.bitmap[31] ^= 1;
+        assert!(!verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b"credits"), &flipped));
+
+        let mut missing = proof.clone();
+        missing.siblings.pop();
+        assert!(!verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b"credits"), &missing));
+
+        let mut altered = proof.clone();
+        altered.siblings[0][0] ^= 1;
+        assert!(!verify_sparse_proof::<Sha3_256>(tree.root(), &key, Some(b"credits"), &altered));
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<SparseProof>(&json).unwrap(), proof);
+    }
+
+    #[test]
+    fn neighbouring_keys() {
+        // Keys sharing all bits but the last have sibling leaves
+        let mut tree = SparseMerkleTree::new();
+        let mut left = [0xffu8; 32];
+        left[31] = 0xfe;
+        let right = [0xffu8; 32];
+
+        tree.set(&left, b"left").unwrap();
+        tree.set(&right, b"right").unwrap();
+        assert_eq!(tree.get(&left).unwrap(), Some(b"left".to_vec()));
+        assert_eq!(tree.get(&right).unwrap(), Some(b"right".to_vec()));
+
+        let proof = tree.prove(&left).unwrap();
+        assert_eq!(proof.siblings, vec![hash_leaf::<Sha3_256>(&right, b"right")]);
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &left, Some(b"left"), &proof));
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &right, Some(b"right"), &tree.prove(&right).unwrap()));
+    }
+
+    #[test]
+    fn file_backed_tree() {
+        let dir = TestDir::new("sparse-tree");
+        let root = {
+            let mut tree = SparseMerkleTree::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap());
+            for id in 0..10 {
+                tree.set(&account(id), b"credits").unwrap();
+            }
+            *tree.root()
+        };
+
+        // The root and the values survive a restart
+        let mut tree = SparseMerkleTree::<Sha3_256, _>::with_store(FileStore::open(&dir.0).unwrap());
+        assert_eq!(*tree.root(), root);
+        assert_eq!(tree.store().roots().len(), 10);
+        for id in 0..10 {
+            assert_eq!(tree.get(&account(id)).unwrap(), Some(b"credits".to_vec()));
+        }
+
+        tree.delete(&account(0)).unwrap();
+        let mut memory = SparseMerkleTree::new();
+        for id in 1..10 {
+            memory.set(&account(id), b"credits").unwrap();
+        }
+        assert_eq!(memory.root(), tree.root());
+        assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &account(0), None, &tree.prove(&account(0)).unwrap()));
+    }
+
+    #[test]
+    fn prune() {
+        let mut tree = SparseMerkleTree::new();
+        for id in 0..10 {
+            tree.set(&account(id), b"credits").unwrap();
+        }
+        let nodes = tree.store().node_count();
+        let audited = *tree.root();
+
+        // Rewritten and deleted leaves, and the paths to them, are freed
+        tree.set(&account(0), b"more credits").unwrap();
+        tree.delete(&account(1)).unwrap();
+        let pinned = [audited].iter().cloned().collect();
+        let report = tree.prune(&Retention::Pinned(pinned)).unwrap();
+        assert_eq!(tree.store().roots().len(), 2);
+        assert!(report.nodes_freed > 0);
+        assert_eq!(tree.get(&account(0)).unwrap(), Some(b"more credits".to_vec()));
+        assert_eq!(tree.get(&account(1)).unwrap(), None);
+
+        // Each key of the audited root still has its own path, nothing else is left
+        let report = tree.prune(&Retention::LastRoots(1)).unwrap();
+        assert!(report.nodes_freed > 0);
+        assert!(tree.store().node_count() < nodes);
+        for id in 2..10 {
+            assert_eq!(tree.get(&account(id)).unwrap(), Some(b"credits".to_vec()));
+            assert!(verify_sparse_proof::<Sha3_256>(tree.root(), &account(id), Some(b"credits"), &tree.prove(&account(id)).unwrap()));
+        }
+        assert_eq!(tree.prune(&Retention::LastRoots(1)).unwrap(), PruneReport::default());
+    }
+}
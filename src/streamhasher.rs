@@ -0,0 +1,204 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::marker::PhantomData;
+
+use crate::merkletree::{Hash, HashScheme, MerkleHasher, Sha3_256};
+
+/**
+ * Root of a balanced MerkleTree computed from leaves fed one at a time,
+ * e.g. the lines of a day's meter readings, without keeping them.
+ *
+ * Only the roots of the perfect subtrees over the leaves so far are kept,
+ * one per bit set in the leaf count, so memory is O(log n) and each leaf costs
+ * O(1) amortized hashes. The complete layout depends on the final leaf count
+ * from the first level up and cannot be streamed.
+ */
+pub struct StreamHasher<H = Sha3_256> {
+    pending: Vec<Hash>, // perfect subtree roots, largest (leftmost) first
+    leaf_count: usize,
+    scheme: HashScheme,
+    hasher: PhantomData<fn() -> H>,
+}
+impl<H> Default for StreamHasher<H> {
+    fn default() -> Self {
+        StreamHasher::with_scheme(HashScheme::default())
+    }
+}
+
+impl StreamHasher {
+    pub fn new() -> StreamHasher {
+        StreamHasher::default()
+    }
+
+    /**
+     * Root of the leaves yielded by `data`, the same as
+     * `MerkleTree::from_data(&data).root()` without collecting them.
+     */
+    pub fn root_of<I: IntoIterator>(data: I) -> Hash where I::Item: AsRef<[u8]> {
+        let mut hasher = StreamHasher::new();
+        hasher.extend(data);
+        hasher.root()
+    }
+}
+
+impl<H> StreamHasher<H> {
+    pub fn with_scheme(scheme: HashScheme) -> Self {
+        StreamHasher{
+            pending: Vec::new(),
+            leaf_count: 0,
+            scheme,
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    pub fn scheme(&self) -> HashScheme {
+        self.scheme
+    }
+}
+
+impl<H: MerkleHasher> StreamHasher<H> {
+    pub fn push(&mut self, data: &[u8]) {
+        let mut hash = self.scheme.hash_leaf::<H>(data);
+
+        // Like a binary increment: every trailing 1 bit of the count is a
+        // perfect subtree of the same size as the new one, they merge
+        let mut count = self.leaf_count;
+        while count % 2 == 1 {
+            let left = self.pending.pop().unwrap();
+            hash = self.scheme.hash_branch::<H>(&left, &hash);
+            count /= 2;
+        }
+
+        self.pending.push(hash);
+        self.leaf_count += 1;
+    }
+
+    /**
+     * Pushes every line of `reader` as a leaf, without its "\n" or "\r\n"
+     * terminator as for BufRead::lines, and returns the number of lines.
+     * Lines need not be UTF-8, a single line is held in memory at a time.
+     * `reader` is buffered here, e.g. a File or TcpStream can be passed as is.
+     */
+    pub fn push_lines<R: Read>(&mut self, reader: R) -> io::Result<usize> {
+        for_each_line(reader, |line| self.push(line))
+    }
+
+    /**
+     * Root of the leaves pushed so far, the hash of no data if none was.
+     * Carried nodes move up unchanged, so the smaller subtrees on the right
+     * fold into the larger ones on their left.
+     */
+    pub fn root(&self) -> Hash {
+        let mut pending = self.pending.iter().rev();
+
+        match pending.next() {
+            Some(last) => pending.fold(*last, |right, left| self.scheme.hash_branch::<H>(left, &right)),
+            None => H::hash(&[]),
+        }
+    }
+}
+
+impl<H: MerkleHasher, T: AsRef<[u8]>> Extend<T> for StreamHasher<H> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, data: I) {
+        for d in data {
+            self.push(d.as_ref());
+        }
+    }
+}
+
+/**
+ * Calls `f` on every line of `reader` without its terminator, see StreamHasher::push_lines.
+ */
+pub(crate) fn for_each_line<R: Read, F: FnMut(&[u8])>(reader: R, mut f: F) -> io::Result<usize> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut count = 0;
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        f(&line);
+        line.clear();
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkletree::{Layout, MerkleTree, Keccak256};
+
+    /**
+     * Reader without buffering that yields at most 3 bytes per read.
+     */
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn make_readings(amount: usize) -> Vec<Vec<u8>> {
+        (0..amount).map(|i| format!("meter-{},2020-03-01T00:{:02},{}.5", i % 7, i % 60, i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn same_root_as_tree() {
+        let readings = make_readings(300);
+
+        for leaves_count in 1..=readings.len() {
+            let data = &readings[..leaves_count];
+            assert_eq!(StreamHasher::root_of(data), *MerkleTree::from_data(data).unwrap().root());
+
+            let mut hasher = StreamHasher::<Keccak256>::with_scheme(HashScheme::Legacy);
+            hasher.extend(data);
+            assert_eq!(hasher.leaf_count(), leaves_count);
+            assert_eq!(hasher.root(), *MerkleTree::<Keccak256>::build(data, HashScheme::Legacy, Layout::Balanced).unwrap().root());
+
+            // One pending hash per bit set in the leaf count
+            assert_eq!(hasher.pending.len(), leaves_count.count_ones() as usize);
+        }
+
+        assert_eq!(StreamHasher::root_of(Vec::<Vec<u8>>::new()), Sha3_256::hash(&[]));
+    }
+
+    #[test]
+    fn lines() {
+        let readings = make_readings(1000);
+        let csv = readings.join(&b"\n"[..]);
+
+        let mut hasher = StreamHasher::new();
+        assert_eq!(hasher.push_lines(&csv[..]).unwrap(), 1000);
+        assert_eq!(hasher.root(), *MerkleTree::from_data(&readings).unwrap().root());
+
+        // A final terminator and CRLF endings do not change the leaves
+        let crlf = [readings.join(&b"\r\n"[..]), b"\r\n".to_vec()].concat();
+        let mut hasher = StreamHasher::new();
+        assert_eq!(hasher.push_lines(io::BufReader::with_capacity(16, &crlf[..])).unwrap(), 1000);
+        assert_eq!(hasher.root(), *MerkleTree::from_data(&readings).unwrap().root());
+
+        // Any Read source, split at arbitrary points
+        let mut hasher = StreamHasher::new();
+        assert_eq!(hasher.push_lines(Trickle(&crlf)).unwrap(), 1000);
+        assert_eq!(hasher.root(), *MerkleTree::from_data(&readings).unwrap().root());
+
+        // Hashing can resume with more data
+        let mut hasher = StreamHasher::new();
+        hasher.push_lines(&readings[..400].join(&b"\n"[..])[..]).unwrap();
+        hasher.extend(&readings[400..]);
+        assert_eq!(hasher.root(), *MerkleTree::from_data(&readings).unwrap().root());
+    }
+}
@@ -0,0 +1,279 @@
+use std::marker::PhantomData;
+
+use serde::{Serialize, Deserialize};
+
+use crate::merkletree::{Hash, Layout, MerkleError, MerkleHasher, Side, Sha3_256, hex_hash};
+use crate::merkletree::{LEAF_PREFIX, BRANCH_PREFIX, level_pairs, level_offsets, path_sides};
+
+/**
+ * Node of a sum tree: the hash of the subtree and the total of its amounts.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumNode {
+    #[serde(with = "hex_hash")]
+    pub hash: Hash,
+    pub sum: u64,
+}
+
+impl SumNode {
+    pub fn leaf<H: MerkleHasher>(data: &[u8], amount: u64) -> SumNode {
+        SumNode{
+            hash: H::hash(&[&[LEAF_PREFIX], &amount.to_be_bytes(), data]),
+            sum: amount,
+        }
+    }
+
+    /**
+     * Both sums are hashed, not only their total, so that a sibling cannot
+     * claim part of the other child's amount. None if the total overflows.
+     */
+    pub fn branch<H: MerkleHasher>(left: &SumNode, right: &SumNode) -> Option<SumNode> {
+        Some(SumNode{
+            hash: H::hash(&[&[BRANCH_PREFIX], &left.hash, &left.sum.to_be_bytes(), &right.hash, &right.sum.to_be_bytes()]),
+            sum: left.sum.checked_add(right.sum)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumProofStep {
+    pub side: Side,
+    #[serde(with = "hex_hash")]
+    pub hash: Hash,
+    pub sum: u64,
+}
+
+/**
+ * Inclusion proof of the leaf at `leaf_index`, which also proves that the
+ * leaf's amount is part of the root total: every sibling carries its sum.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumProof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    pub path: Vec<SumProofStep>,
+}
+
+/**
+ * Merkle sum tree, e.g. of kWh delivered or credits held per participant,
+ * for proof-of-reserves style audits: the root commits to the total.
+ *
+ * Same balanced shape and storage as MerkleTree. Amounts are unsigned,
+ * so no leaf can offset the others, and a total that would overflow is rejected.
+ */
+pub struct MerkleSumTree<H = Sha3_256> {
+    nodes: Vec<SumNode>,
+    offsets: Vec<usize>,
+    pairs: Vec<usize>,
+    leaf_count: usize,
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl MerkleSumTree {
+    pub fn from_entries<T: AsRef<[u8]>>(entries: &[(T, u64)]) -> Result<MerkleSumTree, MerkleError> {
+        MerkleSumTree::build(entries)
+    }
+}
+
+impl<H: MerkleHasher> MerkleSumTree<H> {
+    pub fn build<T: AsRef<[u8]>>(entries: &[(T, u64)]) -> Result<Self, MerkleError> {
+        if entries.is_empty() {
+            return Err(MerkleError::EmptyInput);
+        }
+
+        let pairs = level_pairs(Layout::Balanced, entries.len());
+        let offsets = level_offsets(&pairs, entries.len());
+        let mut nodes = Vec::with_capacity(*offsets.last().unwrap());
+
+        for (data, amount) in entries {
+            nodes.push(SumNode::leaf::<H>(data.as_ref(), *amount));
+        }
+
+        for (level, level_pairs) in pairs.iter().enumerate() {
+            let (start, end) = (offsets[level], offsets[level + 1]);
+            let carried_start = start + 2 * level_pairs;
+
+            for pair in (start..carried_start).step_by(2) {
+                let branch = SumNode::branch::<H>(&nodes[pair], &nodes[pair + 1]).ok_or(MerkleError::SumOverflow)?;
+                nodes.push(branch);
+            }
+            for carried in carried_start..end {
+                let carried = nodes[carried];
+                nodes.push(carried);
+            }
+        }
+
+        Ok(MerkleSumTree{
+            nodes,
+            offsets,
+            pairs,
+            leaf_count: entries.len(),
+            hasher: PhantomData,
+        })
+    }
+
+    pub fn root(&self) -> &SumNode {
+        self.nodes.last().unwrap()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.root().sum
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    pub fn make_proof(&self, leaf_index: usize) -> Result<SumProof, MerkleError> {
+        if leaf_index >= self.leaf_count {
+            return Err(MerkleError::UnknownLeaf{index: leaf_index, leaf_count: self.leaf_count});
+        }
+
+        let mut path = Vec::with_capacity(self.pairs.len());
+        let mut position = leaf_index;
+        for (level, pairs) in self.pairs.iter().enumerate() {
+            let nodes = &self.nodes[self.offsets[level]..self.offsets[level + 1]];
+
+            if position < 2 * pairs {
+                let (side, sibling) = match position % 2 {
+                    1 => (Side::Left, nodes[position - 1]),
+                    _ => (Side::Right, nodes[position + 1]),
+                };
+                path.push(SumProofStep{side, hash: sibling.hash, sum: sibling.sum});
+                position /= 2;
+            } else {
+                position -= pairs;
+            }
+        }
+
+        Ok(SumProof{
+            leaf_index,
+            leaf_count: self.leaf_count,
+            path,
+        })
+    }
+}
+
+/**
+ * Checks that `data` with `amount` is the leaf at `proof.leaf_index` of the
+ * tree committed by `root`, so that `amount` is counted in `root.sum`.
+ */
+pub fn verify_sum_proof<H: MerkleHasher>(root: &SumNode, data: &[u8], amount: u64, proof: &SumProof) -> bool {
+    match path_sides(Layout::Balanced, proof.leaf_index, proof.leaf_count) {
+        Some(sides) if sides.len() == proof.path.len() => {
+            if sides.iter().zip(&proof.path).any(|(side, step)| *side != step.side) {
+                return false;
+            }
+        }
+        _ => return false,
+    }
+
+    let mut node = SumNode::leaf::<H>(data, amount);
+    for step in &proof.path {
+        let sibling = SumNode{hash: step.hash, sum: step.sum};
+        let branch = match step.side {
+            Side::Left => SumNode::branch::<H>(&sibling, &node),
+            Side::Right => SumNode::branch::<H>(&node, &sibling),
+        };
+        node = match branch {
+            Some(branch) => branch,
+            None => return false,
+        };
+    }
+
+    node == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_entries(amount: usize) -> Vec<(Vec<u8>, u64)> {
+        (0..amount).map(|i| (format!("Participant {}", i).into_bytes(), (i as u64 + 1) * 100)).collect()
+    }
+
+    #[test]
+    fn totals() {
+        for leaves_count in 1..=33 {
+            let entries = make_entries(leaves_count);
+            let tree = MerkleSumTree::from_entries(&entries).unwrap();
+
+            assert_eq!(tree.leaf_count(), leaves_count);
+            assert_eq!(tree.total(), entries.iter().map(|(_, amount)| amount).sum::<u64>());
+        }
+
+        assert!(matches!(MerkleSumTree::from_entries::<Vec<u8>>(&[]), Err(MerkleError::EmptyInput)));
+
+        // Amounts are committed: same data, different amounts, different roots
+        let mut entries = make_entries(4);
+        let root = *MerkleSumTree::from_entries(&entries).unwrap().root();
+        entries[0].1 += 1;
+        entries[1].1 -= 1;
+        let moved = *MerkleSumTree::from_entries(&entries).unwrap().root();
+        assert_eq!(moved.sum, root.sum);
+        assert_ne!(moved.hash, root.hash);
+    }
+
+    #[test]
+    fn sum_proofs() {
+        for leaves_count in 1..=17 {
+            let entries = make_entries(leaves_count);
+            let tree = MerkleSumTree::from_entries(&entries).unwrap();
+            let root = tree.root();
+
+            for (i, (data, amount)) in entries.iter().enumerate() {
+                let proof = tree.make_proof(i).unwrap();
+                assert!(verify_sum_proof::<Sha3_256>(root, data, *amount, &proof));
+                assert!(!verify_sum_proof::<Sha3_256>(root, data, amount + 1, &proof));
+                assert!(!verify_sum_proof::<Sha3_256>(root, data, amount - 1, &proof));
+                assert!(!verify_sum_proof::<Sha3_256>(&SumNode{sum: root.sum + 1, ..*root}, data, *amount, &proof));
+
+                // A sibling cannot take over part of the leaf's amount
+                if let Some(step) = proof.path.first() {
+                    let mut shifted = proof.clone();
+                    shifted.path[0].sum = step.sum + 50;
+                    assert!(!verify_sum_proof::<Sha3_256>(root, data, amount - 50, &shifted));
+                }
+            }
+            assert!(tree.make_proof(leaves_count).is_err());
+        }
+    }
+
+    #[test]
+    fn overflowing_sums() {
+        let entries = vec![(b"a".to_vec(), u64::MAX), (b"b".to_vec(), 1)];
+        assert!(matches!(MerkleSumTree::from_entries(&entries), Err(MerkleError::SumOverflow)));
+
+        let entries = vec![(b"a".to_vec(), u64::MAX - 1), (b"b".to_vec(), 1)];
+        let tree = MerkleSumTree::from_entries(&entries).unwrap();
+        assert_eq!(tree.total(), u64::MAX);
+
+        // A forged sibling sum that would wrap around to a small total is rejected
+        let proof = SumProof{
+            leaf_index: 0,
+            leaf_count: 2,
+            path: vec![SumProofStep{side: Side::Right, hash: [0u8; 32], sum: u64::MAX}],
+        };
+        let leaf = SumNode::leaf::<Sha3_256>(b"a", 2);
+        let wrapped = SumNode{
+            hash: Sha3_256::hash(&[&[BRANCH_PREFIX], &leaf.hash, &2u64.to_be_bytes(), &[0u8; 32], &u64::MAX.to_be_bytes()]),
+            sum: 1,
+        };
+        assert!(!verify_sum_proof::<Sha3_256>(&wrapped, b"a", 2, &proof));
+    }
+
+    #[test]
+    fn negative_amounts() {
+        // Amounts are unsigned: a negative sum cannot even be decoded
+        let entries = make_entries(3);
+        let tree = MerkleSumTree::from_entries(&entries).unwrap();
+        let proof = tree.make_proof(2).unwrap();
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<SumProof>(&json).unwrap(), proof);
+
+        let negative = json.replacen(&format!("\"sum\":{}", proof.path[0].sum), "\"sum\":-100", 1);
+        assert_ne!(negative, json);
+        assert!(serde_json::from_str::<SumProof>(&negative).is_err());
+    }
+}
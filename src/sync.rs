@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use faster_hex::hex_string;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+
+use crate::merkletree::{Hash, HashScheme, Layout, MerkleError, MerkleHasher, MerkleTree, Sha3_256, bytes_from_hex, hex_hash, hex_hashes};
+
+/**
+ * Largest frame accepted from a peer.
+ */
+const MAX_FRAME: usize = 16 << 20;
+
+/**
+ * Most leaves asked in a single request.
+ */
+const LEAF_BATCH: usize = 4096;
+
+/**
+ * Most leaf bytes in a response. Hex encoded, they take half a frame at most,
+ * the rest of the leaves asked are sent in later responses.
+ */
+const LEAF_BYTES: usize = MAX_FRAME / 4;
+
+/**
+ * Most a replica takes from its peer in a sync, so that a peer announcing a
+ * huge tree cannot make it allocate without bound.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncLimits {
+    pub max_leaves: usize,
+    /**
+     * Total size of the leaves fetched.
+     */
+    pub max_bytes: usize,
+}
+impl Default for SyncLimits {
+    fn default() -> Self {
+        SyncLimits{
+            max_leaves: 1 << 24,
+            max_bytes: 1 << 30,
+        }
+    }
+}
+
+/**
+ * Request of a lagging node to its peer.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    Summary,
+    /**
+     * Nodes of `level` at `positions`, all roots of perfect subtrees.
+     */
+    Nodes { level: usize, positions: Vec<usize> },
+    Leaves { indices: Vec<usize> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    Summary {
+        leaf_count: usize,
+        scheme: HashScheme,
+        #[serde(with = "hex_hash")]
+        root: Hash,
+    },
+    Nodes {
+        #[serde(with = "hex_hashes")]
+        hashes: Vec<Hash>,
+    },
+    /**
+     * Leaf data for the first indices asked, at least one.
+     */
+    Leaves {
+        #[serde(with = "hex_data")]
+        data: Vec<Vec<u8>>,
+    },
+    Error { message: String },
+}
+
+/**
+ * Serializes leaf data as hex strings, half the size of JSON arrays of numbers at most.
+ */
+mod hex_data {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(data: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(data.iter().map(|datum| hex_string(datum).unwrap()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        let hexes = Vec::<String>::deserialize(deserializer)?;
+        hexes.iter()
+            .map(|hex| bytes_from_hex(hex).ok_or_else(|| serde::de::Error::custom("expected a hex string")))
+            .collect()
+    }
+}
+
+/**
+ * Data items and their balanced Merkle tree, e.g. the trades of a node,
+ * kept in sync with a peer's. The replica may be empty.
+ */
+pub struct Replica<H = Sha3_256> {
+    data: Vec<Vec<u8>>,
+    tree: Option<MerkleTree<H>>,
+}
+
+impl Replica {
+    pub fn from_data(data: Vec<Vec<u8>>) -> Replica {
+        Replica::build(data)
+    }
+}
+
+impl<H: MerkleHasher> Replica<H> {
+    pub fn build(data: Vec<Vec<u8>>) -> Self {
+        // No tree without data
+        let tree = MerkleTree::build(&data, HashScheme::default(), Layout::Balanced).ok();
+
+        Replica{
+            data,
+            tree,
+        }
+    }
+
+    pub fn data(&self) -> &[Vec<u8>] {
+        &self.data
+    }
+
+    pub fn tree(&self) -> Option<&MerkleTree<H>> {
+        self.tree.as_ref()
+    }
+
+    /**
+     * Root of the tree, the hash of no data when empty as in RFC 6962.
+     */
+    pub fn root(&self) -> Hash {
+        match &self.tree {
+            Some(tree) => *tree.root(),
+            None => H::hash(&[]),
+        }
+    }
+
+    fn summary(&self) -> Response {
+        Response::Summary{
+            leaf_count: self.data.len(),
+            scheme: HashScheme::default(),
+            root: self.root(),
+        }
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        let error = |message: &str| Response::Error{message: message.to_string()};
+
+        match request {
+            Request::Summary => self.summary(),
+            Request::Nodes{level, positions} => {
+                let mut hashes = Vec::with_capacity(positions.len());
+                for position in positions {
+                    let perfect = position.checked_add(1)
+                        .and_then(|end| end.checked_mul(1usize.checked_shl(level as u32)?))
+                        .is_some_and(|end| end <= self.data.len());
+                    match self.tree.as_ref().and_then(|tree| tree.node(level, position)) {
+                        Some(hash) if perfect => hashes.push(*hash),
+                        _ => return error("Not a perfect subtree"),
+                    }
+                }
+                Response::Nodes{hashes}
+            }
+            Request::Leaves{indices} => {
+                let mut data = Vec::new();
+                let mut bytes = 0;
+                for index in indices {
+                    let datum = match self.data.get(index) {
+                        Some(datum) if datum.len() > LEAF_BYTES => return error("Leaf too large for a frame"),
+                        Some(datum) => datum,
+                        None => return error("Leaf index out of range"),
+                    };
+
+                    bytes += datum.len();
+                    if bytes > LEAF_BYTES {
+                        break; // asked again by the peer
+                    }
+                    data.push(datum.clone());
+                }
+                Response::Leaves{data}
+            }
+        }
+    }
+}
+
+/**
+ * What a sync exchanged with the peer.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncReport {
+    pub requests: usize,
+    pub nodes_fetched: usize,
+    pub leaves_fetched: usize,
+}
+
+/**
+ * Answers the requests read from `stream` until the peer closes it.
+ */
+pub fn serve<H: MerkleHasher, S: Read + Write>(replica: &Replica<H>, mut stream: S) -> io::Result<()> {
+    while let Some(request) = read_frame::<_, Request>(&mut stream)? {
+        write_frame(&mut stream, &replica.respond(request))?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Brings `replica` to the state of the peer at the other end of `stream`,
+ * within the default SyncLimits.
+ *
+ * Starting from the root, the perfect subtrees over aligned ranges of leaves,
+ * which both balanced trees share whatever their sizes, are compared level by
+ * level: one request per level, for the nodes whose parents differ. Then only
+ * the leaves that differ, or that the replica lacks, are fetched. The replica
+ * is only replaced if the rebuilt tree has the peer's root.
+ */
+pub fn sync<H: MerkleHasher, S: Read + Write>(replica: &mut Replica<H>, stream: S) -> Result<SyncReport, MerkleError> {
+    sync_with_limits(replica, stream, SyncLimits::default())
+}
+
+/**
+ * sync, failing with a ProtocolViolation if the peer has more leaves or
+ * sends more bytes than `limits`. The replica is then left as it was.
+ */
+pub fn sync_with_limits<H: MerkleHasher, S: Read + Write>(replica: &mut Replica<H>, mut stream: S, limits: SyncLimits) -> Result<SyncReport, MerkleError> {
+    let mut report = SyncReport::default();
+
+    let (remote_count, remote_root) = match exchange(&mut stream, &Request::Summary, &mut report)? {
+        Response::Summary{leaf_count, scheme, root} if scheme == HashScheme::default() => (leaf_count, root),
+        Response::Summary{..} => return Err(MerkleError::IncompatibleTrees),
+        _ => return Err(violation("Unexpected response to a summary request")),
+    };
+    if remote_count == replica.data.len() && remote_root == replica.root() {
+        return Ok(report);
+    }
+    if remote_count > limits.max_leaves {
+        return Err(violation(&format!("{} leaves announced, at most {} accepted", remote_count, limits.max_leaves)));
+    }
+
+    let local_count = replica.data.len();
+    let shared = local_count.min(remote_count);
+    let mut missing: Vec<Range<usize>> = Vec::new();
+
+    // Ranges [start, start + 2^level) still to compare
+    let width = local_count.max(remote_count).checked_next_power_of_two().ok_or_else(|| violation("Leaf count too large"))?;
+    let mut level = width.trailing_zeros() as usize;
+    let mut starts = vec![0];
+    while !starts.is_empty() {
+        let mut compare = Vec::new();
+        let mut split = Vec::new();
+        for start in starts {
+            let end = start + (1 << level);
+            if start >= remote_count {
+                continue; // only the replica has these leaves, they are dropped
+            } else if start >= local_count {
+                missing.push(start..end.min(remote_count));
+            } else if end <= shared {
+                compare.push(start);
+            } else {
+                split.push(start);
+            }
+        }
+
+        if !compare.is_empty() {
+            let positions: Vec<usize> = compare.iter().map(|start| start >> level).collect();
+            let request = Request::Nodes{level, positions: positions.clone()};
+            let hashes = match exchange(&mut stream, &request, &mut report)? {
+                Response::Nodes{hashes} if hashes.len() == positions.len() => hashes,
+                _ => return Err(violation("Unexpected response to a nodes request")),
+            };
+            report.nodes_fetched += hashes.len();
+
+            let tree = replica.tree.as_ref().ok_or(MerkleError::EmptyInput)?;
+            for ((start, position), hash) in compare.iter().zip(&positions).zip(&hashes) {
+                if tree.node(level, *position) != Some(hash) {
+                    match level {
+                        0 => missing.push(*start..start + 1),
+                        _ => split.push(*start),
+                    }
+                }
+            }
+        }
+
+        if level == 0 {
+            break;
+        }
+        level -= 1;
+        split.sort_unstable();
+        starts = split.iter().flat_map(|start| vec![*start, start + (1 << level)]).collect();
+    }
+
+    // In batches, and only as far as the peer delivers: its leaf count is not trusted yet.
+    // The peer answers for the first leaves of a batch, the others are asked again.
+    missing.sort_unstable_by_key(|range| range.start);
+    let mut indices = missing.into_iter().flatten();
+    let mut batch: Vec<usize> = Vec::new();
+    let mut fetched = HashMap::new();
+    let mut fetched_bytes = 0usize;
+    loop {
+        batch.extend(indices.by_ref().take(LEAF_BATCH - batch.len()));
+        if batch.is_empty() {
+            break;
+        }
+
+        let data = match exchange(&mut stream, &Request::Leaves{indices: batch.clone()}, &mut report)? {
+            Response::Leaves{data} if !data.is_empty() && data.len() <= batch.len() => data,
+            _ => return Err(violation("Unexpected response to a leaves request")),
+        };
+        fetched_bytes = data.iter().fold(fetched_bytes, |bytes, datum| bytes.saturating_add(datum.len()));
+        if fetched_bytes > limits.max_bytes {
+            return Err(violation(&format!("More than {} bytes of leaves sent", limits.max_bytes)));
+        }
+        report.leaves_fetched += data.len();
+        fetched.extend(batch.drain(..data.len()).zip(data));
+    }
+
+    let data: Vec<Vec<u8>> = (0..remote_count)
+        .map(|i| fetched.remove(&i).unwrap_or_else(|| replica.data[i].clone()))
+        .collect();
+    let synced = Replica::build(data);
+    if synced.root() != remote_root {
+        return Err(violation("Leaves sent do not hash to the root announced"));
+    }
+    *replica = synced;
+
+    Ok(report)
+}
+
+fn violation(reason: &str) -> MerkleError {
+    MerkleError::ProtocolViolation(reason.to_string())
+}
+
+fn exchange<S: Read + Write>(stream: &mut S, request: &Request, report: &mut SyncReport) -> Result<Response, MerkleError> {
+    report.requests += 1;
+    write_frame(stream, request)?;
+
+    match read_frame(stream)? {
+        Some(Response::Error{message}) => Err(MerkleError::Rejected(message)),
+        Some(response) => Ok(response),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection").into()),
+    }
+}
+
+/**
+ * Writes the message as JSON, prefixed by its length (u32 BE).
+ * Messages longer than a peer accepts are not sent.
+ */
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    let length = match u32::try_from(json.len()) {
+        Ok(length) if json.len() <= MAX_FRAME => length,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame too large")),
+    };
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(&json)?;
+    writer.flush()
+}
+
+/**
+ * Reads a message written by write_frame, None if the stream ended before it.
+ */
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too large"));
+    }
+    let mut json = vec![0u8; length];
+    reader.read_exact(&mut json)?;
+
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+/**
+ * One end of an in-process byte stream, see channel_pair.
+ */
+pub struct ChannelStream {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+/**
+ * Two connected streams: what is written to one is read from the other.
+ * Reading returns end of stream once the other end is dropped.
+ */
+pub fn channel_pair() -> (ChannelStream, ChannelStream) {
+    let (a_sender, b_receiver) = mpsc::channel();
+    let (b_sender, a_receiver) = mpsc::channel();
+
+    (ChannelStream{sender: a_sender, receiver: a_receiver, buffer: Vec::new()},
+     ChannelStream{sender: b_sender, receiver: b_receiver, buffer: Vec::new()})
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(bytes) => self.buffer = bytes,
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let count = buf.len().min(self.buffer.len());
+        buf[..count].copy_from_slice(&self.buffer[..count]);
+        self.buffer.drain(..count);
+
+        Ok(count)
+    }
+}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Peer dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn make_trades(amount: usize) -> Vec<Vec<u8>> {
+        (0..amount).map(|i| format!("Trade {}", i).into_bytes()).collect()
+    }
+
+    /**
+     * Syncs `local` with a peer holding `remote`, over channels.
+     */
+    fn sync_over_channels(local: &mut Replica, remote: Vec<Vec<u8>>) -> Result<SyncReport, MerkleError> {
+        let (client, server) = channel_pair();
+        let peer = thread::spawn(move || serve(&Replica::from_data(remote), server).unwrap());
+
+        let report = sync(local, client);
+        peer.join().unwrap();
+        report
+    }
+
+    #[test]
+    fn frames() {
+        let (mut a, mut b) = channel_pair();
+        write_frame(&mut a, &Request::Leaves{indices: vec![1, 2]}).unwrap();
+        write_frame(&mut a, &Request::Summary).unwrap();
+        assert_eq!(read_frame(&mut b).unwrap(), Some(Request::Leaves{indices: vec![1, 2]}));
+        assert_eq!(read_frame(&mut b).unwrap(), Some(Request::Summary));
+
+        drop(a);
+        assert_eq!(read_frame::<_, Request>(&mut b).unwrap(), None);
+
+        let mut oversized = &((MAX_FRAME + 1) as u32).to_be_bytes()[..];
+        assert!(read_frame::<_, Request>(&mut oversized).is_err());
+
+        // Nor are they written
+        let message = Response::Error{message: "x".repeat(MAX_FRAME)};
+        assert_eq!(write_frame(&mut Vec::new(), &message).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // Leaves as hex, empty ones included
+        let leaves = Response::Leaves{data: vec![vec![0xab, 0x01], Vec::new()]};
+        assert_eq!(serde_json::to_string(&leaves).unwrap(), r#"{"Leaves":{"data":["ab01",""]}}"#);
+        assert_eq!(serde_json::from_str::<Response>(r#"{"Leaves":{"data":["ab01",""]}}"#).unwrap(), leaves);
+        assert!(serde_json::from_str::<Response>(r#"{"Leaves":{"data":["abc"]}}"#).is_err());
+    }
+
+    #[test]
+    fn sync_changed_leaves() {
+        let trades = make_trades(1000);
+        let mut lagging = trades.clone();
+        for i in [3, 500, 501, 999].iter() {
+            lagging[*i] = b"Stale trade".to_vec();
+        }
+
+        let mut local = Replica::from_data(lagging);
+        let report = sync_over_channels(&mut local, trades.clone()).unwrap();
+        assert_eq!(local.data(), &trades[..]);
+        assert_eq!(local.root(), Replica::from_data(trades.clone()).root());
+
+        // Only the differing leaves, and a few nodes per level and difference
+        assert_eq!(report.leaves_fetched, 4);
+        assert!(report.nodes_fetched <= 4 * 2 * 10);
+        assert!(report.requests <= 2 + 11);
+
+        // Nothing more to fetch
+        let report = sync_over_channels(&mut local, trades).unwrap();
+        assert_eq!(report, SyncReport{requests: 1, ..SyncReport::default()});
+    }
+
+    #[test]
+    fn sync_different_sizes() {
+        let trades = make_trades(77);
+        for local_count in 0..=90 {
+            for changed in [None, Some(local_count / 2)].iter() {
+                let mut lagging = make_trades(local_count);
+                if let Some(i) = changed.filter(|i| *i < local_count.min(77)) {
+                    lagging[i] = b"Stale trade".to_vec();
+                }
+
+                let mut local = Replica::from_data(lagging);
+                let report = sync_over_channels(&mut local, trades.clone()).unwrap();
+                assert_eq!(local.data(), &trades[..]);
+
+                let appended = 77usize.saturating_sub(local_count);
+                let stale = changed.filter(|i| *i < local_count.min(77)).is_some() as usize;
+                assert_eq!(report.leaves_fetched, appended + stale);
+            }
+        }
+
+        // Syncing with an empty peer empties the replica
+        let mut local = Replica::from_data(make_trades(5));
+        sync_over_channels(&mut local, Vec::new()).unwrap();
+        assert!(local.data().is_empty() && local.tree().is_none());
+    }
+
+    #[test]
+    fn lying_peer() {
+        // The peer's leaves do not match the root it announced
+        let (mut client, mut server) = channel_pair();
+        let peer = thread::spawn(move || {
+            let honest = Replica::from_data(make_trades(8));
+            while let Some(request) = read_frame::<_, Request>(&mut server).unwrap() {
+                let response = match request {
+                    Request::Leaves{indices} => Response::Leaves{data: indices.iter().map(|_| b"Forged".to_vec()).collect()},
+                    request => honest.respond(request),
+                };
+                write_frame(&mut server, &response).unwrap();
+            }
+        });
+
+        let stale = make_trades(6);
+        let mut local = Replica::from_data(stale.clone());
+        assert!(matches!(sync(&mut local, &mut client), Err(MerkleError::ProtocolViolation(_))));
+        assert_eq!(local.data(), &stale[..]);
+
+        drop(client);
+        peer.join().unwrap();
+
+        // Requests for nodes that are not perfect subtrees are rejected
+        let replica = Replica::from_data(make_trades(5));
+        assert!(matches!(replica.respond(Request::Nodes{level: 2, positions: vec![1]}), Response::Error{..}));
+        assert!(matches!(replica.respond(Request::Nodes{level: 70, positions: vec![0]}), Response::Error{..}));
+        assert!(matches!(replica.respond(Request::Leaves{indices: vec![5]}), Response::Error{..}));
+    }
+
+    /**
+     * Syncs 5 trades with a peer announcing `leaf_count` leaves, and sending
+     * leaves of 1 KB for any index asked.
+     */
+    fn sync_with_bloated_peer(leaf_count: usize, limits: SyncLimits) -> Result<SyncReport, MerkleError> {
+        let (mut client, mut server) = channel_pair();
+        let peer = thread::spawn(move || {
+            let honest = Replica::from_data(make_trades(8));
+            while let Some(request) = read_frame::<_, Request>(&mut server).unwrap() {
+                let response = match request {
+                    Request::Summary => Response::Summary{leaf_count, scheme: HashScheme::default(), root: [0u8; 32]},
+                    Request::Leaves{indices} => Response::Leaves{data: indices.iter().map(|_| vec![0u8; 1000]).collect()},
+                    request => honest.respond(request),
+                };
+                write_frame(&mut server, &response).unwrap();
+            }
+        });
+
+        let mut local = Replica::from_data(make_trades(5));
+        let result = sync_with_limits(&mut local, &mut client, limits);
+        assert_eq!(local.data().len(), 5);
+
+        drop(client);
+        peer.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn absurd_leaf_counts() {
+        // Announcing more leaves than accepted fails the sync, without a panic or exhausting memory
+        for leaf_count in [usize::MAX, 1 << 62, (1 << 24) + 1] {
+            let result = sync_with_bloated_peer(leaf_count, SyncLimits::default());
+            assert!(matches!(result, Err(MerkleError::ProtocolViolation(_))));
+        }
+
+        // As does sending more bytes than accepted
+        let limits = SyncLimits{max_leaves: 1 << 20, max_bytes: 100_000};
+        match sync_with_bloated_peer(1 << 20, limits) {
+            Err(MerkleError::ProtocolViolation(reason)) => assert!(reason.contains("100000 bytes")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn large_leaves() {
+        // Hex encoded, 3000 leaves of 2 KB are more than a frame: they come in several responses
+        let trades: Vec<Vec<u8>> = (0..3000).map(|i| format!("Trade {:02000}", i).into_bytes()).collect();
+        let mut local = Replica::from_data(trades[..10].to_vec());
+        let report = sync_over_channels(&mut local, trades.clone()).unwrap();
+        assert_eq!(local.data(), &trades[..]);
+        assert_eq!(report.leaves_fetched, 2990);
+        assert!(report.requests > 2);
+
+        // A leaf that does not fit in a frame is refused
+        let replica = Replica::from_data(vec![vec![0u8; LEAF_BYTES + 1]]);
+        assert!(matches!(replica.respond(Request::Leaves{indices: vec![0]}), Response::Error{..}));
+    }
+
+    #[test]
+    fn sync_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let trades = make_trades(300);
+
+        let remote = trades.clone();
+        let peer = thread::spawn(move || {
+            let replica = Replica::from_data(remote);
+            let (stream, _) = listener.accept().unwrap();
+            serve(&replica, stream).unwrap();
+        });
+
+        let mut lagging = make_trades(250);
+        lagging[42] = b"Stale trade".to_vec();
+        let mut local = Replica::from_data(lagging);
+        let report = sync(&mut local, TcpStream::connect(address).unwrap()).unwrap();
+        peer.join().unwrap();
+
+        assert_eq!(local.data(), &trades[..]);
+        assert_eq!(report.leaves_fetched, 50 + 1);
+    }
+}